@@ -45,6 +45,10 @@ elif [ "$1" = "POST" ]; then
     #                                        bits. Must be a multiple of 8.
     #   additional_slave_SAE_IDs: [Optional] A list of additional sae ids to
     #                                        associate with this key.
+    #   extension_mandatory:      [Optional] A list of extensions the KME must
+    #                                        support, e.g. 'route_type'.
+    #   extension_optional:       [Optional] A list of extensions the KME may
+    #                                        ignore if unsupported.
 
     curl                                          \
         -i                                        \
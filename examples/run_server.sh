@@ -6,4 +6,6 @@ script_dir="$( cd "$(dirname "$0")" || exit 1; pwd -P )"
 ETSI_014_REF_IMPL_TLS_ROOT_CRT=${CERTS_DIR}/root.crt \
 ETSI_014_REF_IMPL_TLS_PRIVATE_KEY=${CERTS_DIR}/kme_001.key \
 ETSI_014_REF_IMPL_TLS_CERT=${CERTS_DIR}/kme_001.crt \
+ETSI_014_REF_IMPL_MASTER_KEY_FILE=${CERTS_DIR}/master.key \
+ETSI_014_REF_IMPL_SAE_KMES=sae_001=kme_001,sae_002=kme_001,sae_additional_123=kme_001,sae_additional_456=kme_001 \
 SQLX_OFFLINE=true cargo run
@@ -0,0 +1,7 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+ALTER TABLE keys
+    DROP COLUMN IF EXISTS key_id_extension,
+    DROP COLUMN IF EXISTS key_extension,
+    DROP COLUMN IF EXISTS key_container_extension;
@@ -0,0 +1,7 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+ALTER TABLE keys
+    ADD COLUMN key_id_extension        JSONB,
+    ADD COLUMN key_extension           JSONB,
+    ADD COLUMN key_container_extension JSONB;
@@ -0,0 +1,13 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+CREATE TABLE key_pool (
+    id            uuid        NOT NULL PRIMARY KEY,
+    source_kme_id TEXT        NOT NULL CHECK(ltrim(rtrim(source_kme_id)) != ''),
+    target_kme_id TEXT        NOT NULL CHECK(ltrim(rtrim(target_kme_id)) != ''),
+    size          INT         NOT NULL CHECK(size > 0),
+    content       TEXT        NOT NULL CHECK(ltrim(rtrim(content)) != ''),
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON key_pool (source_kme_id, target_kme_id, created_at);
@@ -0,0 +1,8 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+CREATE TABLE sae_kmes (
+    sae_id     TEXT        NOT NULL PRIMARY KEY CHECK(ltrim(rtrim(sae_id)) != ''),
+    kme_id     TEXT        NOT NULL CHECK(ltrim(rtrim(kme_id)) != ''),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
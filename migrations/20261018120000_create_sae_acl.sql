@@ -0,0 +1,9 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+CREATE TABLE sae_acl (
+    master_sae_id TEXT        NOT NULL CHECK(ltrim(rtrim(master_sae_id)) != ''),
+    slave_sae_id  TEXT        NOT NULL CHECK(ltrim(rtrim(slave_sae_id)) != ''),
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (master_sae_id, slave_sae_id)
+);
@@ -0,0 +1,4 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+ALTER TABLE keys DROP COLUMN IF EXISTS expires_at;
@@ -0,0 +1,6 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+ALTER TABLE keys ADD COLUMN expires_at TIMESTAMPTZ;
+
+CREATE INDEX ON keys (expires_at);
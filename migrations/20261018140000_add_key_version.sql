@@ -0,0 +1,8 @@
+-- SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+-- SPDX-License-Identifier: AGPL-3.0-only
+
+-- Version of the master key the content is encrypted with, zero for content
+-- stored in plaintext.
+ALTER TABLE keys ADD COLUMN key_version INT NOT NULL DEFAULT 0;
+
+CREATE INDEX ON keys (key_version);
@@ -1,6 +0,0 @@
-SELECT count(*) as "count!"
-FROM keys
-WHERE 
-    id = $1 AND
-    master_sae_id = $2 AND
-    active = TRUE;
\ No newline at end of file
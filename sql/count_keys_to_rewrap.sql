@@ -0,0 +1,3 @@
+SELECT
+    (SELECT count(*) FROM keys WHERE key_version <> $1) +
+    (SELECT count(*) FROM key_pool WHERE key_version <> $1) as "count!";
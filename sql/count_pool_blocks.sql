@@ -0,0 +1,6 @@
+SELECT count(*) as "count!"
+FROM key_pool
+WHERE 
+    source_kme_id = $1 AND
+    target_kme_id = $2 AND
+    size = $3;
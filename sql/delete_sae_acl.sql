@@ -0,0 +1,4 @@
+DELETE FROM sae_acl
+WHERE 
+    master_sae_id = $1 AND
+    slave_sae_id = $2;
@@ -0,0 +1,2 @@
+DELETE FROM sae_kmes
+WHERE sae_id = $1;
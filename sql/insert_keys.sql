@@ -1,2 +1,23 @@
-INSERT INTO keys (id, master_sae_id, slave_sae_id, size, content)
-VALUES ($1, $2, $3, $4, $5);
\ No newline at end of file
+INSERT INTO keys (
+    id,
+    master_sae_id,
+    slave_sae_id,
+    size,
+    content,
+    key_id_extension,
+    key_extension,
+    key_container_extension,
+    expires_at,
+    key_version
+)
+SELECT *, $9::timestamptz, $10::int
+FROM UNNEST(
+    $1::uuid[],
+    $2::text[],
+    $3::text[],
+    $4::int[],
+    $5::text[],
+    $6::jsonb[],
+    $7::jsonb[],
+    $8::jsonb[]
+);
\ No newline at end of file
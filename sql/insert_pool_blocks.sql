@@ -0,0 +1,3 @@
+INSERT INTO key_pool (id, source_kme_id, target_kme_id, size, content, key_version)
+SELECT id, $2, $3, $4, content, $6
+FROM UNNEST($1::uuid[], $5::text[]) AS blocks(id, content);
@@ -0,0 +1,3 @@
+INSERT INTO sae_acl (master_sae_id, slave_sae_id)
+VALUES ($1, $2)
+ON CONFLICT (master_sae_id, slave_sae_id) DO NOTHING;
@@ -0,0 +1,4 @@
+INSERT INTO sae_kmes (sae_id, kme_id)
+SELECT sae_id, kme_id
+FROM UNNEST($1::text[], $2::text[]) AS sae_kmes(sae_id, kme_id)
+ON CONFLICT (sae_id) DO NOTHING;
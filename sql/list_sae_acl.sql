@@ -0,0 +1,3 @@
+SELECT master_sae_id, slave_sae_id
+FROM sae_acl
+ORDER BY master_sae_id, slave_sae_id;
@@ -0,0 +1,3 @@
+SELECT sae_id, kme_id
+FROM sae_kmes
+ORDER BY sae_id;
@@ -0,0 +1,2 @@
+DELETE FROM keys
+WHERE expires_at <= NOW();
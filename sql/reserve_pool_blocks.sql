@@ -0,0 +1,13 @@
+DELETE FROM key_pool
+WHERE id IN (
+    SELECT id
+    FROM key_pool
+    WHERE 
+        source_kme_id = $1 AND
+        target_kme_id = $2 AND
+        size = $3
+    ORDER BY created_at, id
+    LIMIT $4
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING id, content, key_version, created_at;
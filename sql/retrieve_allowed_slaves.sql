@@ -0,0 +1,5 @@
+SELECT slave_sae_id
+FROM sae_acl
+WHERE 
+    master_sae_id = $1 AND
+    slave_sae_id = ANY($2);
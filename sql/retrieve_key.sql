@@ -1,8 +0,0 @@
-SELECT id, content, size
-FROM keys
-WHERE 
-    id = $1 AND
-    master_sae_id = $2 AND
-    slave_sae_id = $3 AND
-    active = TRUE
-;
\ No newline at end of file
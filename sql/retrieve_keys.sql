@@ -0,0 +1,54 @@
+WITH consumed AS (
+    UPDATE keys
+    SET active = FALSE
+    WHERE 
+        id = ANY($1) AND
+        master_sae_id = $2 AND
+        slave_sae_id = $3 AND
+        active = TRUE AND
+        (expires_at IS NULL OR expires_at > NOW())
+    RETURNING
+        id,
+        content,
+        key_version,
+        size,
+        key_id_extension,
+        key_extension,
+        key_container_extension
+)
+SELECT
+    requested.id as "id!",
+    consumed.content as "content?",
+    consumed.key_version as "key_version?",
+    consumed.size as "size?",
+    consumed.key_id_extension,
+    consumed.key_extension,
+    consumed.key_container_extension,
+    EXISTS (
+        SELECT 1
+        FROM keys
+        WHERE
+            keys.id = requested.id AND
+            keys.master_sae_id = $2
+    ) as "exists_for_master!",
+    EXISTS (
+        SELECT 1
+        FROM keys
+        WHERE
+            keys.id = requested.id AND
+            keys.master_sae_id = $2 AND
+            keys.slave_sae_id = $3
+    ) as "exists_for_slave!",
+    EXISTS (
+        SELECT 1
+        FROM keys
+        WHERE
+            keys.id = requested.id AND
+            keys.master_sae_id = $2 AND
+            keys.slave_sae_id = $3 AND
+            keys.expires_at <= NOW()
+    ) as "is_expired!"
+FROM UNNEST($1::uuid[]) WITH ORDINALITY AS requested(id, position)
+LEFT JOIN consumed ON consumed.id = requested.id
+ORDER BY requested.position
+;
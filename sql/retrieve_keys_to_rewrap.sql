@@ -0,0 +1,10 @@
+SELECT
+    id,
+    master_sae_id,
+    slave_sae_id,
+    content,
+    key_version
+FROM keys
+WHERE key_version <> $1
+LIMIT $2
+FOR UPDATE SKIP LOCKED;
@@ -0,0 +1,10 @@
+SELECT
+    id,
+    source_kme_id,
+    target_kme_id,
+    content,
+    key_version
+FROM key_pool
+WHERE key_version <> $1
+LIMIT $2
+FOR UPDATE SKIP LOCKED;
@@ -0,0 +1,3 @@
+SELECT sae_id, kme_id
+FROM sae_kmes
+WHERE sae_id = ANY($1);
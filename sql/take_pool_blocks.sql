@@ -0,0 +1,7 @@
+DELETE FROM key_pool
+WHERE 
+    source_kme_id = $1 AND
+    target_kme_id = $2 AND
+    size = $3 AND
+    id = ANY($4)
+RETURNING id, content, key_version;
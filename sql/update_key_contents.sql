@@ -0,0 +1,14 @@
+UPDATE keys
+SET
+    content = rewrapped.content,
+    key_version = $5
+FROM UNNEST(
+    $1::uuid[],
+    $2::text[],
+    $3::text[],
+    $4::text[]
+) AS rewrapped(id, master_sae_id, slave_sae_id, content)
+WHERE
+    keys.id = rewrapped.id AND
+    keys.master_sae_id = rewrapped.master_sae_id AND
+    keys.slave_sae_id = rewrapped.slave_sae_id;
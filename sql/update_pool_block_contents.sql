@@ -0,0 +1,9 @@
+UPDATE key_pool
+SET
+    content = rewrapped.content,
+    key_version = $3
+FROM UNNEST(
+    $1::uuid[],
+    $2::text[]
+) AS rewrapped(id, content)
+WHERE key_pool.id = rewrapped.id;
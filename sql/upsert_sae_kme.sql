@@ -0,0 +1,6 @@
+INSERT INTO sae_kmes (sae_id, kme_id)
+VALUES ($1, $2)
+ON CONFLICT (sae_id) DO UPDATE
+SET 
+    kme_id = EXCLUDED.kme_id,
+    updated_at = NOW();
@@ -2,7 +2,27 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 use actix_web::HttpResponse;
+use std::sync::RwLock;
 
 use crate::error::Error;
 
 pub type CustomResult = Result<HttpResponse, Error>;
+
+// NOTE: The values behind these locks are only ever replaced as a whole, hence
+// a lock poisoned by a panicking thread still holds a consistent value.
+
+/// Returns a copy of the value behind the lock.
+pub fn load<T: Clone>(lock: &RwLock<T>) -> T {
+    match lock.read() {
+        Ok(value) => value.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+/// Replaces the value behind the lock.
+pub fn store<T>(lock: &RwLock<T>, value: T) {
+    match lock.write() {
+        Ok(mut current) => *current = value,
+        Err(poisoned) => *poisoned.into_inner() = value,
+    }
+}
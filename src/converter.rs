@@ -6,6 +6,8 @@ use actix_web::http::StatusCode;
 use base64::Engine;
 use log::error;
 use serde::Deserialize;
+use serde_json::json;
+use zeroize::Zeroizing;
 
 pub fn to_json<'a, T>(json_text: &'a str) -> Result<T, Error>
 where
@@ -14,10 +16,15 @@ where
     match serde_json::from_str::<T>(json_text) {
         Ok(parsed_json) => Ok(parsed_json),
         Err(e) => {
+            // NOTE: Neither the body nor the error message, which may quote
+            // it, are logged, as bodies may carry key material.
             error!(
-                "Failed to parse JSON. \
-                 Error: {:?} Received body: {}",
-                e, json_text
+                "Failed to parse JSON body of {} bytes. Error: {:?} at line {} \
+                 column {}",
+                json_text.len(),
+                e.classify(),
+                e.line(),
+                e.column()
             );
             Err(Error::new(
                 StatusCode::BAD_REQUEST,
@@ -32,14 +39,32 @@ pub fn to_uuid(text: &str) -> Result<uuid::Uuid, Error> {
         Ok(value) => Ok(value),
         Err(e) => {
             error!("Failed to convert {} to UUID. Error: {:?}", text, e);
-            Err(Error::new(
-                StatusCode::BAD_REQUEST,
-                "Invalid key id supplied",
-            ))
+            Err(
+                Error::new(StatusCode::BAD_REQUEST, "Invalid key id supplied")
+                    .with_detail(json!({ "key_ID": text })),
+            )
+        }
+    }
+}
+
+pub fn to_base64(key: &[u8]) -> Zeroizing<String> {
+    Zeroizing::new(base64::engine::general_purpose::STANDARD.encode(key))
+}
+
+pub fn from_base64(key: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
+    match decode_base64(key) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            error!("Failed to decode base64 key material. Error: {:?}", e);
+            Err(Error::internal_server_error())
         }
     }
 }
 
-pub fn to_base64(key: &[u8]) -> String {
-    base64::engine::general_purpose::STANDARD.encode(key)
+/// Decodes base64 key material supplied by a peer, leaving it to the caller
+/// to decide whose mistake a decoding failure is.
+pub fn decode_base64(
+    key: &str,
+) -> Result<Zeroizing<Vec<u8>>, base64::DecodeError> {
+    base64::engine::general_purpose::STANDARD.decode(key).map(Zeroizing::new)
 }
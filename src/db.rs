@@ -4,10 +4,18 @@
 use crate::config::CONFIG;
 use crate::error::Error;
 use log::error;
-use sqlx::PgPool;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
 
-pub async fn establish_connection() -> Result<PgPool, Error> {
-    match PgPool::connect(&CONFIG.db_url).await {
+pub async fn create_pool() -> Result<PgPool, Error> {
+    match PgPoolOptions::new()
+        .max_connections(CONFIG.db_max_connections)
+        .acquire_timeout(Duration::from_secs(CONFIG.db_acquire_timeout_secs))
+        .idle_timeout(Duration::from_secs(CONFIG.db_idle_timeout_secs))
+        .connect(&CONFIG.db_url)
+        .await
+    {
         Ok(pool) => Ok(pool),
         Err(e) => {
             error!("Failed to connect to the database. Error: {:?}", e);
@@ -15,3 +23,25 @@ pub async fn establish_connection() -> Result<PgPool, Error> {
         }
     }
 }
+
+pub async fn begin(pool: &PgPool) -> Result<Transaction<'_, Postgres>, Error> {
+    match pool.begin().await {
+        Ok(transaction) => Ok(transaction),
+        Err(e) => {
+            error!("Failed to start transaction. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+pub async fn commit(
+    transaction: Transaction<'_, Postgres>,
+) -> Result<(), Error> {
+    match transaction.commit().await {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            error!("Failed to commit transaction. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
@@ -2,6 +2,16 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 pub struct Default<'a> {
+    // Server
+    pub server_backlog: u32,
+    pub server_max_connections: usize,
+    pub server_max_connection_rate: usize,
+    pub server_keep_alive_secs: u64,
+    pub server_client_request_timeout_secs: u64,
+    pub server_shutdown_timeout_secs: u64,
+    // TLS
+    pub tls_reload_interval_secs: u64,
+    pub crl_check: &'a str,
     // Keys
     pub key_size: i32,
     pub num_keys: i32,
@@ -9,21 +19,61 @@ pub struct Default<'a> {
     pub max_key_per_request: i32,
     pub max_key_size: i32,
     pub min_key_size: i32,
+    pub key_source: &'a str,
+    pub key_ttl_secs: u64,
+    pub key_purge_interval_secs: u64,
+    // Key pool
+    pub key_pool_block_size: i32,
+    pub key_pool_batch_size: i32,
+    pub key_pool_refill_interval_secs: u64,
     // SAEs
     pub max_additional_saes: i32,
+    pub acl_enabled: bool,
+    pub sae_id_source: &'a str,
     // KMEs
-    pub src_kme_id: &'a str,
-    pub dst_kme_id: &'a str,
+    pub kme_id: &'a str,
+    pub max_relay_hops: u32,
+    // Encryption at rest
+    pub master_key_version: i32,
+    pub key_rotation_batch_size: i64,
+    // Logging
+    pub log_level: &'a str,
+    // Database
+    pub db_max_connections: u32,
+    pub db_acquire_timeout_secs: u64,
+    pub db_idle_timeout_secs: u64,
 }
 
 pub const DEFAULT: Default = Default {
+    server_backlog: 2048,
+    server_max_connections: 25000,
+    server_max_connection_rate: 256,
+    server_keep_alive_secs: 5,
+    server_client_request_timeout_secs: 5,
+    server_shutdown_timeout_secs: 30,
+    tls_reload_interval_secs: 60,
+    crl_check: "leaf",
     key_size: 1024,
     num_keys: 1,
-    max_key_count: 0,
-    max_key_per_request: 0,
-    max_key_size: 0,
-    min_key_size: 0,
-    max_additional_saes: 0,
-    src_kme_id: "src_kme_id",
-    dst_kme_id: "dst_kme_id",
+    max_key_count: 100000,
+    max_key_per_request: 128,
+    max_key_size: 8192,
+    min_key_size: 8,
+    key_source: "random",
+    key_ttl_secs: 86400,
+    key_purge_interval_secs: 60,
+    key_pool_block_size: 256,
+    key_pool_batch_size: 1000,
+    key_pool_refill_interval_secs: 1,
+    max_additional_saes: 16,
+    acl_enabled: false,
+    sae_id_source: "cn",
+    kme_id: "kme_001",
+    max_relay_hops: 8,
+    master_key_version: 0,
+    key_rotation_batch_size: 500,
+    log_level: "info",
+    db_max_connections: 10,
+    db_acquire_timeout_secs: 30,
+    db_idle_timeout_secs: 600,
 };
@@ -1,13 +1,15 @@
 // SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use actix_web::{error, http::StatusCode, HttpResponse};
-use serde_json::json;
+use actix_web::{error, http::StatusCode, web, HttpResponse};
+use log::error;
+use serde_json::{json, Value};
 use std::fmt;
 
 #[derive(Debug)]
 pub struct Error {
     message: String,
+    details: Vec<Value>,
     status_code: StatusCode,
 }
 
@@ -15,29 +17,32 @@ impl Error {
     pub fn new(status_code: StatusCode, msg: &str) -> Self {
         Self {
             message: msg.to_string(),
+            details: Vec::new(),
             status_code,
         }
     }
 
     pub fn internal_server_error() -> Self {
-        Self {
-            message: "".to_string(),
-            status_code: StatusCode::INTERNAL_SERVER_ERROR,
-        }
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
     }
 
     pub fn unauthorized() -> Self {
-        Self {
-            message: "".to_string(),
-            status_code: StatusCode::UNAUTHORIZED,
-        }
+        Self::new(StatusCode::UNAUTHORIZED, "Unauthorized")
     }
 
     pub fn bad_request(msg: &str) -> Self {
-        Self {
-            message: msg.to_string(),
-            status_code: StatusCode::BAD_REQUEST,
-        }
+        Self::new(StatusCode::BAD_REQUEST, msg)
+    }
+
+    pub fn service_unavailable(msg: &str) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, msg)
+    }
+
+    /// Adds an object to the 'details' array of the error response, giving
+    /// the client more information on what caused the error.
+    pub fn with_detail(mut self, detail: Value) -> Self {
+        self.details.push(detail);
+        self
     }
 }
 
@@ -47,22 +52,102 @@ impl fmt::Display for Error {
             f,
             "Error - Status Code: {} Message: {}",
             self.status_code, self.message
-        )
+        )?;
+
+        if !self.details.is_empty() {
+            write!(f, " Details: {}", Value::from(self.details.clone()))?;
+        }
+
+        Ok(())
     }
 }
 
 impl error::ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
-        if self.message.is_empty() {
-            HttpResponse::build(self.status_code).finish()
-        } else {
-            HttpResponse::build(self.status_code).json(json!({
-                "message": self.message
-            }))
+        let mut body = json!({ "message": self.message });
+
+        if !self.details.is_empty() {
+            body["details"] = Value::from(self.details.clone());
         }
+
+        HttpResponse::build(self.status_code).json(body)
     }
 
     fn status_code(&self) -> StatusCode {
         self.status_code
     }
 }
+
+/// Answers query strings the extractor cannot parse with the same body as
+/// any other error.
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|e, _| {
+        error!("Failed to parse query string. Error: {}", e);
+        Error::bad_request("Invalid query parameters supplied.").into()
+    })
+}
+
+/// Answers paths the extractor cannot parse with the same body as any other
+/// error.
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|e, _| {
+        error!("Failed to parse path. Error: {}", e);
+        Error::bad_request("Invalid path parameters supplied.").into()
+    })
+}
+
+/// Answers JSON bodies the extractor cannot parse with the same body as any
+/// other error.
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|e, _| {
+        // NOTE: Deserialization errors may quote the body, hence are not
+        // logged.
+        match e {
+            error::JsonPayloadError::Deserialize(_) => {
+                error!("Failed to parse JSON body")
+            }
+            e => error!("Failed to read JSON body. Error: {}", e),
+        }
+        Error::bad_request("Malformed JSON supplied").into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body, ResponseError};
+    use pretty_assertions::assert_eq;
+
+    async fn response_body(error: &Error) -> Value {
+        let bytes =
+            body::to_bytes(error.error_response().into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_error_without_details() {
+        let error = Error::unauthorized();
+
+        assert_eq!(error.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response_body(&error).await,
+            json!({ "message": "Unauthorized" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_error_with_details() {
+        let error = Error::bad_request("Key not found")
+            .with_detail(json!({ "key_ID": "abc" }))
+            .with_detail(json!({ "key_ID": "def" }));
+
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response_body(&error).await,
+            json!({
+                "message": "Key not found",
+                "details": [{ "key_ID": "abc" }, { "key_ID": "def" }]
+            })
+        );
+    }
+}
@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use actix_web::{
+    delete, get, http::StatusCode, post, put, web, HttpRequest, HttpResponse,
+    Responder,
+};
+use sqlx::PgPool;
+
+use crate::{
+    converter,
+    default::DEFAULT,
+    error::Error,
+    models::{key_rotation::KeyRotationRequest, registry::SaeKmeUpdate},
+    ops,
+};
+
+#[get("/api/v1/admin/saes")]
+pub async fn get_saes(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+
+    Ok::<_, Error>(HttpResponse::Ok().json(ops::registry::list(&pool).await?))
+}
+
+#[put("/api/v1/admin/saes/{sae_id}")]
+pub async fn put_sae(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    sae_id: web::Path<String>,
+    request_body: String,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+    let update = converter::to_json::<SaeKmeUpdate>(&request_body)?;
+
+    ops::registry::register(&pool, &sae_id, &update.kme_id).await?;
+
+    Ok::<_, Error>(HttpResponse::NoContent().finish())
+}
+
+#[delete("/api/v1/admin/saes/{sae_id}")]
+pub async fn delete_sae(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    sae_id: web::Path<String>,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+
+    if !ops::registry::unregister(&pool, &sae_id).await? {
+        return Err(Error::new(StatusCode::NOT_FOUND, "SAE not found"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/api/v1/admin/acl")]
+pub async fn get_acl(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+
+    Ok::<_, Error>(HttpResponse::Ok().json(ops::acl::list(&pool).await?))
+}
+
+#[put("/api/v1/admin/acl/{master_sae_id}/{slave_sae_id}")]
+pub async fn put_acl_entry(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    sae_ids: web::Path<(String, String)>,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+    let (master_sae_id, slave_sae_id) = sae_ids.into_inner();
+
+    ops::acl::allow(&pool, &master_sae_id, &slave_sae_id).await?;
+
+    Ok::<_, Error>(HttpResponse::NoContent().finish())
+}
+
+#[delete("/api/v1/admin/acl/{master_sae_id}/{slave_sae_id}")]
+pub async fn delete_acl_entry(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    sae_ids: web::Path<(String, String)>,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+    let (master_sae_id, slave_sae_id) = sae_ids.into_inner();
+
+    if !ops::acl::revoke(&pool, &master_sae_id, &slave_sae_id).await? {
+        return Err(Error::new(StatusCode::NOT_FOUND, "SAE pair not found"));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/api/v1/admin/key_rotation")]
+pub async fn get_key_rotation(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+
+    Ok::<_, Error>(
+        HttpResponse::Ok().json(ops::key_rotation::status(&pool).await?),
+    )
+}
+
+#[post("/api/v1/admin/key_rotation")]
+pub async fn post_key_rotation(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    request_body: String,
+) -> impl Responder {
+    ops::admin::authorize(&request)?;
+
+    // NOTE: The request body is optional, as all its parameters are.
+    let rotation = match request_body.trim().is_empty() {
+        true => KeyRotationRequest::default(),
+        false => converter::to_json::<KeyRotationRequest>(&request_body)?,
+    };
+
+    let status = ops::key_rotation::start(
+        &pool,
+        rotation.batch_size.unwrap_or(DEFAULT.key_rotation_batch_size),
+    )
+    .await?;
+
+    Ok::<_, Error>(HttpResponse::Accepted().json(status))
+}
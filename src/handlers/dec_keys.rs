@@ -3,7 +3,7 @@
 
 use crate::{
     common::CustomResult, converter, error::Error,
-    models::connection_info::ConnectionInfo, ops::key::get_multiple_keys,
+    models::connection_info::ConnectionInfo, ops,
 };
 use actix_web::{
     get, post,
@@ -13,6 +13,8 @@ use actix_web::{
 use log::error;
 use serde::Deserialize;
 use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashSet;
 
 #[derive(Deserialize, Debug)]
 pub struct RequestParams {
@@ -29,11 +31,13 @@ pub struct RequestParamsElement {
 #[get("/api/v1/keys/{master_sae_id}/dec_keys")]
 pub async fn get(
     request: HttpRequest,
+    pool: web::Data<PgPool>,
     master_sae_id: web::Path<String>,
     request_params: Query<RequestParamsElement>,
 ) -> impl Responder {
     service_request(
         &request,
+        &pool,
         &RequestParams {
             key_ids: vec![request_params.into_inner()],
         },
@@ -45,6 +49,7 @@ pub async fn get(
 #[post("/api/v1/keys/{master_sae_id}/dec_keys")]
 pub async fn post(
     request: HttpRequest,
+    pool: web::Data<PgPool>,
     master_sae_id: web::Path<String>,
     request_body: String,
 ) -> impl Responder {
@@ -56,11 +61,12 @@ pub async fn post(
         }
     };
 
-    service_request(&request, &params, master_sae_id.to_string()).await
+    service_request(&request, &pool, &params, master_sae_id.to_string()).await
 }
 
 async fn service_request(
     request: &HttpRequest,
+    pool: &PgPool,
     params: &RequestParams,
     master_sae_id: String,
 ) -> CustomResult {
@@ -68,21 +74,43 @@ async fn service_request(
     let slave_sae_id = &ConnectionInfo::new(request)?.sae_id;
 
     validate_sae_ids(&master_sae_id, slave_sae_id)?;
+    ops::acl::authorize(
+        pool,
+        &master_sae_id,
+        std::slice::from_ref(slave_sae_id),
+    )
+    .await?;
+
+    let policy = ops::policy::for_pair(&master_sae_id, slave_sae_id);
+    ops::policy::validate_num_keys(&policy, requested_key_ids.len())?;
 
-    let keys =
-        get_multiple_keys(&requested_key_ids, &master_sae_id, slave_sae_id)
-            .await?;
+    let key_container = ops::key::get_multiple_keys(
+        pool,
+        &requested_key_ids,
+        &master_sae_id,
+        slave_sae_id,
+    )
+    .await?;
 
-    Ok(HttpResponse::Ok().json(json!({ "keys": keys })))
+    Ok(HttpResponse::Ok().json(key_container))
 }
 
 fn validate_and_parse_parameters(
     params: &RequestParams,
 ) -> Result<Vec<uuid::Uuid>, Error> {
     let mut key_ids: Vec<uuid::Uuid> = Vec::with_capacity(params.key_ids.len());
+    let mut unique_key_ids: HashSet<uuid::Uuid> =
+        HashSet::with_capacity(params.key_ids.len());
 
     for key_element in &params.key_ids {
-        key_ids.push(converter::to_uuid(&key_element.key_id)?);
+        let key_id = converter::to_uuid(&key_element.key_id)?;
+
+        if !unique_key_ids.insert(key_id) {
+            return Err(Error::bad_request("Duplicate key IDs supplied")
+                .with_detail(json!({ "key_ID": key_id })));
+        }
+
+        key_ids.push(key_id);
     }
 
     Ok(key_ids)
@@ -100,3 +128,38 @@ fn validate_sae_ids(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App};
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use sqlx::postgres::PgPoolOptions;
+
+    #[actix_web::test]
+    async fn test_malformed_query_returns_error_body() {
+        // The query is rejected before the database is used.
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/key_store")
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(crate::error::query_config())
+                .service(get),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/api/v1/keys/sae_001/dec_keys?key_IDs=123")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            test::read_body_json::<Value, _>(response).await,
+            json!({ "message": "Invalid query parameters supplied." })
+        );
+    }
+}
@@ -6,14 +6,23 @@ use actix_web::{
     web::{self, Query},
     HttpRequest, HttpResponse, Responder,
 };
+use chrono::SecondsFormat;
 use log::error;
 use serde::Deserialize;
 use serde_json::json;
+use sqlx::PgPool;
 use std::collections::HashSet;
 
 use crate::{
-    common::CustomResult, converter, default::DEFAULT, error::Error,
-    models::connection_info::ConnectionInfo, ops,
+    common::CustomResult,
+    converter, db,
+    default::DEFAULT,
+    error::Error,
+    models::{connection_info::ConnectionInfo, key::KeyContainer},
+    ops::{
+        self,
+        extension::{ExtensionContext, ExtensionParams, EXTENSIONS},
+    },
 };
 
 #[derive(Deserialize, Debug)]
@@ -22,6 +31,8 @@ pub struct RequestParams {
     size: Option<i32>,
     #[serde(rename = "additional_slave_SAE_IDs")]
     additional_slave_sae_ids: Option<Vec<String>>,
+    extension_mandatory: Option<ExtensionParams>,
+    extension_optional: Option<ExtensionParams>,
 }
 
 impl RequestParams {
@@ -30,6 +41,8 @@ impl RequestParams {
             number: None,
             size: None,
             additional_slave_sae_ids: None,
+            extension_mandatory: None,
+            extension_optional: None,
         }
     }
 }
@@ -37,6 +50,7 @@ impl RequestParams {
 #[get("/api/v1/keys/{slave_sae_id}/enc_keys")]
 pub async fn get(
     request: HttpRequest,
+    pool: web::Data<PgPool>,
     slave_sae_id: web::Path<String>,
 ) -> impl Responder {
     let params =
@@ -50,12 +64,13 @@ pub async fn get(
             }
         };
 
-    service_request(&request, &params, slave_sae_id.to_string()).await
+    service_request(&request, &pool, &params, slave_sae_id.to_string()).await
 }
 
 #[post("/api/v1/keys/{slave_sae_id}/enc_keys")]
 pub async fn post(
     request: HttpRequest,
+    pool: web::Data<PgPool>,
     slave_sae_id: web::Path<String>,
     request_body: String,
 ) -> impl Responder {
@@ -70,32 +85,128 @@ pub async fn post(
         },
     };
 
-    service_request(&request, &params, slave_sae_id.to_string()).await
+    service_request(&request, &pool, &params, slave_sae_id.to_string()).await
 }
 
 async fn service_request(
     request: &HttpRequest,
+    pool: &PgPool,
     params: &RequestParams,
     slave_sae_id: String,
 ) -> CustomResult {
-    let key_size = params.size.unwrap_or(DEFAULT.key_size);
+    let master_sae_id = &ConnectionInfo::new(request)?.sae_id;
+    let slave_sae_ids = validate_and_parse_slave_sae_ids(
+        pool,
+        master_sae_id,
+        &slave_sae_id,
+        params,
+    )
+    .await?;
+
+    let policy = ops::policy::for_pair(master_sae_id, &slave_sae_id);
+    let key_size = params.size.unwrap_or(policy.key_size);
     let num_keys = params.number.unwrap_or(DEFAULT.num_keys);
 
     ops::key::validate_key_size(key_size)?;
     ops::key::validate_num_keys(num_keys)?;
+    ops::policy::validate_num_additional_sae_ids(
+        &policy,
+        slave_sae_ids.len() - 1,
+    )?;
 
-    let master_sae_id = &ConnectionInfo::new(request)?.sae_id;
-    let slave_sae_ids =
-        validate_and_parse_slave_sae_ids(master_sae_id, &slave_sae_id, params)?;
+    // Every master/slave pair the keys are shared with must abide by its own
+    // policy.
+    let mut pair_policies = Vec::with_capacity(slave_sae_ids.len());
+    for slave_id in &slave_sae_ids {
+        let pair_policy = ops::policy::for_pair(master_sae_id, slave_id);
+        ops::policy::validate_key_size(&pair_policy, key_size)?;
+        ops::policy::validate_num_keys(
+            &pair_policy,
+            usize::try_from(num_keys).unwrap_or(usize::MAX),
+        )?;
+        pair_policies.push(pair_policy);
+    }
+
+    let expires_at = ops::key::expiry(&pair_policies);
 
-    let generated_keys = ops::key::generate_random_keys(key_size, num_keys)?;
+    let target_kme_id =
+        ops::registry::target_kme_id(pool, &slave_sae_ids).await?;
+    let link = ops::kme::link_towards(&target_kme_id)?;
 
-    ops::key::save_keys(&generated_keys, master_sae_id, &slave_sae_ids).await?;
+    let mut extensions = EXTENSIONS.process(
+        params.extension_mandatory.as_deref().unwrap_or_default(),
+        params.extension_optional.as_deref().unwrap_or_default(),
+        &ExtensionContext {
+            is_relayed: link.target_kme_id != target_kme_id,
+        },
+    )?;
 
-    Ok(HttpResponse::Ok().json(json!({ "keys": generated_keys })))
+    // The slave SAEs are told when the keys expire through the key extension.
+    if let Some(expires_at) = expires_at {
+        extensions.key.insert(
+            "expires_at".to_string(),
+            json!(expires_at.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        );
+    }
+
+    // The key material is taken out of the pool and handed to the slave SAEs
+    // atomically, such that no key material is lost if saving the keys fails.
+    let mut transaction = db::begin(pool).await?;
+
+    let (mut generated_keys, key_blocks): (Vec<_>, Vec<_>) =
+        ops::key_pool::reserve_keys(
+            &mut transaction,
+            &link,
+            key_size,
+            num_keys,
+        )
+        .await?
+        .into_iter()
+        .map(|reserved_key| (reserved_key.key, reserved_key.blocks))
+        .unzip();
+
+    for key in &mut generated_keys {
+        key.id_extension = extensions.key_id_value();
+        key.extension = extensions.key_value();
+    }
+
+    let key_container = KeyContainer {
+        keys: generated_keys,
+        key_container_extension: extensions.container_value(),
+    };
+
+    if link.is_remote() {
+        let handover = ops::relay::forward_keys(
+            &link,
+            master_sae_id,
+            &slave_sae_ids,
+            &key_container,
+            key_blocks,
+            expires_at,
+        );
+
+        // Keys handed over to another KME are taken out of the pool first,
+        // such that the pool is not locked while waiting for that KME.
+        db::commit(transaction).await?;
+        ops::relay::hand_over(pool, handover).await?;
+    } else {
+        ops::key::save_keys(
+            &mut transaction,
+            &key_container,
+            master_sae_id,
+            &slave_sae_ids,
+            expires_at,
+        )
+        .await?;
+
+        db::commit(transaction).await?;
+    }
+
+    Ok(HttpResponse::Ok().json(key_container))
 }
 
-fn validate_and_parse_slave_sae_ids(
+async fn validate_and_parse_slave_sae_ids(
+    pool: &PgPool,
     master_sae_id: &str,
     slave_sae_id: &String,
     params: &RequestParams,
@@ -127,7 +238,13 @@ fn validate_and_parse_slave_sae_ids(
         ));
     }
 
-    Ok(Vec::from_iter(slave_sae_ids.into_iter().cloned()))
+    let slave_sae_ids = Vec::from_iter(slave_sae_ids.into_iter().cloned());
+
+    // A master SAE may only share keys with the slave SAEs it was granted
+    // access to.
+    ops::acl::authorize(pool, master_sae_id, &slave_sae_ids).await?;
+
+    Ok(slave_sae_ids)
 }
 
 fn validate_sae_id(sae_id: &String) -> Result<&String, Error> {
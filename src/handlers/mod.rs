@@ -1,6 +1,8 @@
 // SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
 // SPDX-License-Identifier: AGPL-3.0-only
 
+pub mod admin;
 pub mod dec_keys;
 pub mod enc_keys;
+pub mod peer;
 pub mod status;
@@ -0,0 +1,230 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use log::error;
+use sqlx::PgPool;
+
+use crate::{
+    common::CustomResult,
+    config::CONFIG,
+    converter, db,
+    error::Error,
+    models::{
+        key::KeyContainer,
+        peer::{PeerKeys, PoolBlock, PoolBlocks},
+    },
+    ops,
+};
+
+#[post("/api/v1/peer/key_pool")]
+pub async fn post_key_pool(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    request_body: String,
+) -> impl Responder {
+    let blocks = converter::to_json::<PoolBlocks>(&request_body)?;
+
+    receive_pool_blocks(&request, &pool, &blocks).await
+}
+
+#[post("/api/v1/peer/keys")]
+pub async fn post_keys(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    request_body: String,
+) -> impl Responder {
+    let peer_keys = converter::to_json::<PeerKeys>(&request_body)?;
+
+    receive_keys(&request, &pool, peer_keys).await
+}
+
+async fn receive_pool_blocks(
+    request: &HttpRequest,
+    pool: &PgPool,
+    blocks: &PoolBlocks,
+) -> CustomResult {
+    let link = ops::peer::authorize(
+        request,
+        &blocks.source_kme_id,
+        &blocks.target_kme_id,
+    )?;
+
+    validate_pool_blocks(blocks, CONFIG.key_pool_block_size)?;
+
+    let mut transaction = db::begin(pool).await?;
+    ops::key_pool::insert_blocks(&mut transaction, &link, &blocks.blocks)
+        .await?;
+    db::commit(transaction).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn receive_keys(
+    request: &HttpRequest,
+    pool: &PgPool,
+    peer_keys: PeerKeys,
+) -> CustomResult {
+    let link = ops::peer::authorize(
+        request,
+        &peer_keys.source_kme_id,
+        &peer_keys.target_kme_id,
+    )?;
+
+    validate_peer_keys(&peer_keys)?;
+
+    let target_kme_id =
+        ops::registry::target_kme_id(pool, &peer_keys.slave_sae_ids).await?;
+
+    let mut transaction = db::begin(pool).await?;
+
+    let (keys, taken_blocks): (Vec<_>, Vec<_>) = ops::key_pool::take_keys(
+        &mut transaction,
+        &link,
+        peer_keys.size,
+        &peer_keys.keys,
+    )
+    .await?
+    .into_iter()
+    .map(|taken_key| (taken_key.key, taken_key.blocks))
+    .unzip();
+
+    let key_container = KeyContainer {
+        keys,
+        key_container_extension: peer_keys.key_container_extension,
+    };
+
+    // The keys are either meant for SAEs connected to this KME, or relayed
+    // to the next KME on the path to the slave SAEs.
+    if target_kme_id == CONFIG.kme_id {
+        ops::key::save_keys(
+            &mut transaction,
+            &key_container,
+            &peer_keys.master_sae_id,
+            &peer_keys.slave_sae_ids,
+            peer_keys.expires_at,
+        )
+        .await?;
+
+        db::commit(transaction).await?;
+
+        return Ok(HttpResponse::NoContent().finish());
+    }
+
+    let handover = ops::relay::relay_keys(
+        &mut transaction,
+        &ops::kme::link_towards(&target_kme_id)?,
+        &peer_keys.master_sae_id,
+        &peer_keys.slave_sae_ids,
+        &key_container,
+        peer_keys.hop_count,
+        peer_keys.expires_at,
+    )
+    .await?;
+
+    db::commit(transaction).await?;
+
+    // The previous hop puts its blocks back into its pool when the keys are
+    // rejected, hence so does this KME.
+    if let Err(e) = ops::relay::hand_over(pool, handover).await {
+        let taken_blocks: Vec<PoolBlock> =
+            taken_blocks.into_iter().flatten().collect();
+        if let Err(return_error) =
+            ops::key_pool::return_blocks(pool, &link, &taken_blocks).await
+        {
+            error!(
+                "Failed to return {} blocks to key pool for link {} -> {}: {}",
+                taken_blocks.len(),
+                link.source_kme_id,
+                link.target_kme_id,
+                return_error
+            );
+        }
+        return Err(e);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn validate_pool_blocks(
+    blocks: &PoolBlocks,
+    block_size: i32,
+) -> Result<(), Error> {
+    if blocks.size != block_size {
+        error!(
+            "Received blocks of {} bits, while the block size is {} bits",
+            blocks.size, block_size
+        );
+        return Err(Error::bad_request("Key pool block size mismatch"));
+    }
+
+    Ok(())
+}
+
+fn validate_peer_keys(peer_keys: &PeerKeys) -> Result<(), Error> {
+    if peer_keys.slave_sae_ids.is_empty() {
+        return Err(Error::bad_request("No slave SAEs supplied"));
+    }
+
+    // Keys are relayed as a whole, hence every hop needs at least one.
+    if peer_keys.keys.is_empty() {
+        return Err(Error::bad_request("No keys supplied"));
+    }
+
+    ops::key::validate_key_size(peer_keys.size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::peer::PeerKey;
+    use test_case::test_case;
+    use uuid::Uuid;
+
+    fn peer_keys(slave_sae_ids: &[&str], size: i32) -> PeerKeys {
+        let key = PeerKey {
+            id: Uuid::new_v4(),
+            id_extension: None,
+            extension: None,
+            block_ids: vec![Uuid::new_v4()],
+            masked_key: None,
+        };
+
+        PeerKeys {
+            source_kme_id: "kme_002".to_string(),
+            target_kme_id: "kme_001".to_string(),
+            master_sae_id: "sae_003".to_string(),
+            slave_sae_ids: slave_sae_ids
+                .iter()
+                .map(|sae_id| sae_id.to_string())
+                .collect(),
+            size,
+            keys: vec![key],
+            key_container_extension: None,
+            hop_count: 0,
+            expires_at: None,
+        }
+    }
+
+    #[test_case(true, 256; "Matching block size")]
+    #[test_case(false, 128; "Block size mismatch")]
+    fn test_validate_pool_blocks(is_ok: bool, size: i32) {
+        let blocks = PoolBlocks {
+            source_kme_id: "kme_002".to_string(),
+            target_kme_id: "kme_001".to_string(),
+            size,
+            blocks: Vec::new(),
+        };
+
+        assert_eq!(validate_pool_blocks(&blocks, 256).is_ok(), is_ok);
+    }
+
+    #[test_case(true, peer_keys(&["sae_001"], 256); "Valid keys")]
+    #[test_case(false, peer_keys(&[], 256); "No slave SAEs")]
+    #[test_case(false, PeerKeys { keys: Vec::new(), ..peer_keys(&["sae_001"], 256) }; "No keys")]
+    #[test_case(false, peer_keys(&["sae_001"], 0); "Zero key size")]
+    #[test_case(false, peer_keys(&["sae_001"], 252); "Key size not divisible by 8")]
+    fn test_validate_peer_keys(is_ok: bool, peer_keys: PeerKeys) {
+        assert_eq!(validate_peer_keys(&peer_keys).is_ok(), is_ok);
+    }
+}
@@ -2,36 +2,53 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use sqlx::PgPool;
 
 use crate::{
     common::CustomResult,
-    default::DEFAULT,
     models::{connection_info::ConnectionInfo, status::Status},
+    ops,
 };
 
 #[get("/api/v1/keys/{slave_sae_id}/status")]
 pub async fn get(
     request: HttpRequest,
+    pool: web::Data<PgPool>,
     slave_sae_id: web::Path<String>,
 ) -> impl Responder {
-    service_request(&request, slave_sae_id.to_string())
+    service_request(&request, &pool, slave_sae_id.to_string()).await
 }
 
-fn service_request(
+async fn service_request(
     request: &HttpRequest,
+    pool: &PgPool,
     slave_sae_id: String,
 ) -> CustomResult {
+    let master_sae_id = ConnectionInfo::new(request)?.sae_id;
+
+    let policy = ops::policy::for_pair(&master_sae_id, &slave_sae_id);
+    let target_kme_id =
+        ops::registry::target_kme_id(pool, std::slice::from_ref(&slave_sae_id))
+            .await?;
+    let link = ops::kme::link_towards(&target_kme_id)?;
+    // NOTE: The pool is shared by every pair using the link, and filled for
+    // the largest of their policies.
+    let stored_key_count =
+        ops::key_pool::count_available_keys(pool, &link, policy.key_size)
+            .await?
+            .min(policy.max_key_count);
+
     Ok(HttpResponse::Ok().json(Status {
-        source_kme_id: String::from(DEFAULT.src_kme_id),
-        target_kme_id: String::from(DEFAULT.dst_kme_id),
-        master_sae_id: ConnectionInfo::new(request)?.sae_id,
+        source_kme_id: link.source_kme_id,
+        target_kme_id,
+        master_sae_id,
         slave_sae_id,
-        key_size: DEFAULT.key_size,
-        stored_key_count: 0,
-        max_key_count: 0,
-        max_key_per_request: 0,
-        max_key_size: 0,
-        min_key_size: 0,
-        max_sae_id_count: 0,
+        key_size: policy.key_size,
+        stored_key_count,
+        max_key_count: policy.max_key_count,
+        max_key_per_request: policy.max_key_per_request,
+        max_key_size: policy.max_key_size,
+        min_key_size: policy.min_key_size,
+        max_sae_id_count: policy.max_sae_id_count,
     }))
 }
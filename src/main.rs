@@ -13,15 +13,36 @@ mod handlers;
 mod models;
 mod ops;
 
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::{middleware::Logger, web, App, HttpServer};
 use config::CONFIG;
 use log::info;
+use std::time::Duration;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
     CONFIG.init();
-    db::establish_connection().await.expect("Could not connect to database");
+    env_logger::init_from_env(
+        env_logger::Env::new().default_filter_or(&CONFIG.log_level),
+    );
+    if let Err(e) = ops::peer::reload() {
+        panic!("Failed to build the peer tls configuration. Error: {:?}", e);
+    }
+    for (peer_kme_id, url) in &CONFIG.peer_kmes {
+        info!("Sharing keys with peer KME '{}' at {}", peer_kme_id, url);
+    }
+    let pool = db::create_pool().await.expect("Could not connect to database");
+    ops::registry::init(&pool)
+        .await
+        .expect("Could not register the configured SAEs");
+    actix_web::rt::spawn(ops::key_pool::run_producer(
+        pool.clone(),
+        ops::key_source::from_config(),
+    ));
+    actix_web::rt::spawn(ops::key::run_purger(pool.clone()));
+    actix_web::rt::spawn(ops::server::run_tls_reload_on_hangup());
+    if CONFIG.tls_reload_interval_secs > 0 {
+        actix_web::rt::spawn(ops::server::run_tls_file_watcher());
+    }
 
     info!("Server starting on {}:{}", CONFIG.ip_addr, CONFIG.port_num);
 
@@ -32,9 +53,13 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(error::query_config())
+            .app_data(error::path_config())
+            .app_data(error::json_config())
             // status
             .service(handlers::status::get)
             // enc_keys
@@ -43,9 +68,29 @@ async fn main() -> std::io::Result<()> {
             // dec_keys
             .service(handlers::dec_keys::get)
             .service(handlers::dec_keys::post)
+            // admin
+            .service(handlers::admin::get_saes)
+            .service(handlers::admin::put_sae)
+            .service(handlers::admin::delete_sae)
+            .service(handlers::admin::get_acl)
+            .service(handlers::admin::put_acl_entry)
+            .service(handlers::admin::delete_acl_entry)
+            .service(handlers::admin::get_key_rotation)
+            .service(handlers::admin::post_key_rotation)
+            // peer KMEs
+            .service(handlers::peer::post_key_pool)
+            .service(handlers::peer::post_keys)
     })
     .on_connect(ops::server::add_cert_info_to_request_body)
-    .workers(2)
+    .workers(CONFIG.num_workers.into())
+    .backlog(CONFIG.server_backlog)
+    .max_connections(CONFIG.server_max_connections)
+    .max_connection_rate(CONFIG.server_max_connection_rate)
+    .keep_alive(Duration::from_secs(CONFIG.server_keep_alive_secs))
+    .client_request_timeout(Duration::from_secs(
+        CONFIG.server_client_request_timeout_secs,
+    ))
+    .shutdown_timeout(CONFIG.server_shutdown_timeout_secs)
     .bind_openssl((CONFIG.ip_addr.clone(), CONFIG.port_num), tls_config)?
     .run()
     .await
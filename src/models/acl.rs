@@ -0,0 +1,13 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use serde::Serialize;
+
+/// Master/slave SAE pair allowed to share keys.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AclEntry {
+    #[serde(rename = "master_SAE_ID")]
+    pub master_sae_id: String,
+    #[serde(rename = "slave_SAE_ID")]
+    pub slave_sae_id: String,
+}
@@ -16,8 +16,8 @@ impl ConnectionInfo {
         match request.conn_data::<ConnectionInfo>() {
             Some(conn_info) => Ok(conn_info.clone()),
             None => {
-                error!("Failed to extract 'sae_id' from request");
-                Err(Error::internal_server_error())
+                error!("No SAE ID identified for the connection");
+                Err(Error::unauthorized())
             }
         }
     }
@@ -2,7 +2,10 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 pub struct NewKey {
     pub id: Uuid,
@@ -10,14 +13,75 @@ pub struct NewKey {
     pub slave_sae_id: String,
     pub size: i32,
     pub content: String,
+    pub id_extension: Option<Value>,
+    pub extension: Option<Value>,
+    pub container_extension: Option<Value>,
 }
 
-#[derive(Serialize, Deserialize, sqlx::FromRow)]
+/// Rows to be inserted in the 'keys' table, stored column by column such that
+/// they can be inserted using a single statement.
+#[derive(Default)]
+pub struct NewKeys {
+    pub ids: Vec<Uuid>,
+    pub master_sae_ids: Vec<String>,
+    pub slave_sae_ids: Vec<String>,
+    pub sizes: Vec<i32>,
+    pub contents: Vec<String>,
+    pub id_extensions: Vec<Option<Value>>,
+    pub extensions: Vec<Option<Value>>,
+    pub container_extensions: Vec<Option<Value>>,
+}
+
+impl NewKeys {
+    pub fn push(&mut self, key: NewKey) {
+        self.ids.push(key.id);
+        self.master_sae_ids.push(key.master_sae_id);
+        self.slave_sae_ids.push(key.slave_sae_id);
+        self.sizes.push(key.size);
+        self.contents.push(key.content);
+        self.id_extensions.push(key.id_extension);
+        self.extensions.push(key.extension);
+        self.container_extensions.push(key.container_extension);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+/// Key delivered to an SAE. Its `content` is redacted from the debug output.
+#[derive(Serialize, Deserialize)]
 pub struct Key {
     #[serde(rename = "key_ID")]
     pub id: Uuid,
+    #[serde(
+        rename = "key_ID_extension",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id_extension: Option<Value>,
     #[serde(rename = "key")]
-    pub content: String,
+    pub content: Zeroizing<String>,
+    #[serde(rename = "key_extension", skip_serializing_if = "Option::is_none")]
+    pub extension: Option<Value>,
     #[serde(skip)]
     pub size: i32,
 }
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("id", &self.id)
+            .field("id_extension", &self.id_extension)
+            .field("content", &"<redacted>")
+            .field("extension", &self.extension)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct KeyContainer {
+    pub keys: Vec<Key>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_container_extension: Option<Value>,
+}
@@ -0,0 +1,21 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct KeyRotationRequest {
+    pub batch_size: Option<i64>,
+}
+
+/// Progress of re-encrypting the stored key material with the current master
+/// key.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct KeyRotationStatus {
+    pub running: bool,
+    pub key_version: i32,
+    pub rewrapped_count: u64,
+    pub pending_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
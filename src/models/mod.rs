@@ -1,6 +1,11 @@
 // SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
 // SPDX-License-Identifier: AGPL-3.0-only
 
+pub mod acl;
 pub mod connection_info;
 pub mod key;
+pub mod key_rotation;
+pub mod peer;
+pub mod policy;
+pub mod registry;
 pub mod status;
@@ -0,0 +1,82 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Block of key material in the pool of a link, debug printed without its
+/// `content`.
+#[derive(Serialize, Deserialize)]
+pub struct PoolBlock {
+    pub id: Uuid,
+    pub content: Zeroizing<String>,
+}
+
+impl fmt::Debug for PoolBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolBlock")
+            .field("id", &self.id)
+            .field("content", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Key pool blocks shared by the source KME of a link with its target KME.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PoolBlocks {
+    #[serde(rename = "source_KME_ID")]
+    pub source_kme_id: String,
+    #[serde(rename = "target_KME_ID")]
+    pub target_kme_id: String,
+    pub size: i32,
+    pub blocks: Vec<PoolBlock>,
+}
+
+/// Key delivered to a master SAE, identified by the pool blocks it was built
+/// from.
+///
+/// Keys relayed by an intermediate KME are instead one-time pad encrypted
+/// with the key material of the blocks.
+#[derive(Serialize, Deserialize)]
+pub struct PeerKey {
+    #[serde(rename = "key_ID")]
+    pub id: Uuid,
+    #[serde(
+        rename = "key_ID_extension",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id_extension: Option<Value>,
+    #[serde(rename = "key_extension", skip_serializing_if = "Option::is_none")]
+    pub extension: Option<Value>,
+    pub block_ids: Vec<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub masked_key: Option<String>,
+}
+
+/// Keys to be made available to slave SAEs connected to the target KME of
+/// the link.
+#[derive(Serialize, Deserialize)]
+pub struct PeerKeys {
+    #[serde(rename = "source_KME_ID")]
+    pub source_kme_id: String,
+    #[serde(rename = "target_KME_ID")]
+    pub target_kme_id: String,
+    #[serde(rename = "master_SAE_ID")]
+    pub master_sae_id: String,
+    #[serde(rename = "slave_SAE_IDs")]
+    pub slave_sae_ids: Vec<String>,
+    pub size: i32,
+    pub keys: Vec<PeerKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_container_extension: Option<Value>,
+    /// Number of KMEs the keys were relayed by.
+    #[serde(default)]
+    pub hop_count: u32,
+    /// When the keys expire if not retrieved by the slave SAEs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,59 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyPolicy {
+    pub key_size: i32,
+    pub max_key_count: i32,
+    pub max_key_per_request: i32,
+    pub max_key_size: i32,
+    pub min_key_size: i32,
+    pub max_sae_id_count: i32,
+    /// Seconds after which undelivered keys expire, zero meaning never.
+    pub key_ttl_secs: u64,
+}
+
+#[cfg(test)]
+impl KeyPolicy {
+    /// Policy the tests start from, overriding the limits they exercise.
+    pub fn for_tests() -> Self {
+        Self {
+            key_size: 256,
+            max_key_count: 10,
+            max_key_per_request: 4,
+            max_key_size: 1024,
+            min_key_size: 64,
+            max_sae_id_count: 2,
+            key_ttl_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct KeyPolicyOverride {
+    pub key_size: Option<i32>,
+    pub max_key_count: Option<i32>,
+    pub max_key_per_request: Option<i32>,
+    pub max_key_size: Option<i32>,
+    pub min_key_size: Option<i32>,
+    #[serde(rename = "max_SAE_ID_count")]
+    pub max_sae_id_count: Option<i32>,
+    pub key_ttl_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PairPolicy {
+    #[serde(rename = "master_SAE_ID")]
+    pub master_sae_id: String,
+    #[serde(rename = "slave_SAE_ID")]
+    pub slave_sae_id: String,
+    #[serde(flatten)]
+    pub limits: KeyPolicyOverride,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PolicyFile {
+    pub pairs: Vec<PairPolicy>,
+}
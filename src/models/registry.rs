@@ -0,0 +1,19 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+
+/// Registry entry mapping an SAE to the KME it is connected to.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SaeKme {
+    #[serde(rename = "SAE_ID")]
+    pub sae_id: String,
+    #[serde(rename = "KME_ID")]
+    pub kme_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SaeKmeUpdate {
+    #[serde(rename = "KME_ID")]
+    pub kme_id: String,
+}
@@ -0,0 +1,211 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::models::acl::AclEntry;
+use log::{error, info};
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::HashSet;
+
+/// Checks that the master SAE is allowed to share keys with every one of the
+/// slave SAEs, returning a '401' error listing the slave SAEs it is not
+/// allowed to share keys with.
+///
+/// Any pair is allowed when the access control list is disabled.
+pub async fn authorize(
+    pool: &PgPool,
+    master_sae_id: &str,
+    slave_sae_ids: &[String],
+) -> Result<(), Error> {
+    let allowed_slave_sae_ids = match CONFIG.acl_enabled {
+        true => Some(
+            retrieve_allowed_slaves(pool, master_sae_id, slave_sae_ids).await?,
+        ),
+        false => None,
+    };
+
+    check_access(master_sae_id, slave_sae_ids, allowed_slave_sae_ids.as_ref())
+}
+
+async fn retrieve_allowed_slaves(
+    pool: &PgPool,
+    master_sae_id: &str,
+    slave_sae_ids: &[String],
+) -> Result<HashSet<String>, Error> {
+    match sqlx::query_file!(
+        "sql/retrieve_allowed_slaves.sql",
+        master_sae_id,
+        slave_sae_ids
+    )
+    .fetch_all(pool)
+    .await
+    {
+        Ok(rows) => Ok(rows.into_iter().map(|row| row.slave_sae_id).collect()),
+        Err(e) => {
+            error!("Failed to retrieve access control list. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+/// Checks the slave SAEs against the ones the master SAE was granted access
+/// to, which are not known when the access control list is disabled.
+fn check_access(
+    master_sae_id: &str,
+    slave_sae_ids: &[String],
+    allowed_slave_sae_ids: Option<&HashSet<String>>,
+) -> Result<(), Error> {
+    let allowed_slave_sae_ids = match allowed_slave_sae_ids {
+        Some(allowed_slave_sae_ids) => allowed_slave_sae_ids,
+        None => return Ok(()),
+    };
+
+    let denied_slave_sae_ids: Vec<&String> = slave_sae_ids
+        .iter()
+        .filter(|slave_sae_id| !allowed_slave_sae_ids.contains(*slave_sae_id))
+        .collect();
+
+    if denied_slave_sae_ids.is_empty() {
+        return Ok(());
+    }
+
+    error!(
+        "'{}' is not allowed to share keys with {:?}",
+        master_sae_id, denied_slave_sae_ids
+    );
+
+    Err(denied_slave_sae_ids.into_iter().fold(
+        Error::unauthorized(),
+        |error, slave_sae_id| {
+            error.with_detail(json!({
+                "master_SAE_ID": master_sae_id,
+                "slave_SAE_ID": slave_sae_id
+            }))
+        },
+    ))
+}
+
+pub async fn list(pool: &PgPool) -> Result<Vec<AclEntry>, Error> {
+    match sqlx::query_file_as!(AclEntry, "sql/list_sae_acl.sql")
+        .fetch_all(pool)
+        .await
+    {
+        Ok(entries) => Ok(entries),
+        Err(e) => {
+            error!("Failed to list access control list. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+pub async fn allow(
+    pool: &PgPool,
+    master_sae_id: &str,
+    slave_sae_id: &str,
+) -> Result<(), Error> {
+    if master_sae_id.trim().is_empty()
+        || slave_sae_id.trim().is_empty()
+        || master_sae_id == slave_sae_id
+    {
+        return Err(Error::bad_request("Invalid SAE ID pair supplied"));
+    }
+
+    match sqlx::query_file!(
+        "sql/insert_sae_acl.sql",
+        master_sae_id,
+        slave_sae_id
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(_) => {
+            info!(
+                "Allowed '{}' to share keys with '{}'",
+                master_sae_id, slave_sae_id
+            );
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to update access control list. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+/// Revokes the pair, returning whether it was allowed.
+pub async fn revoke(
+    pool: &PgPool,
+    master_sae_id: &str,
+    slave_sae_id: &str,
+) -> Result<bool, Error> {
+    match sqlx::query_file!(
+        "sql/delete_sae_acl.sql",
+        master_sae_id,
+        slave_sae_id
+    )
+    .execute(pool)
+    .await
+    {
+        Ok(res) => {
+            info!(
+                "Revoked '{}' sharing keys with '{}'",
+                master_sae_id, slave_sae_id
+            );
+            Ok(res.rows_affected() > 0)
+        }
+        Err(e) => {
+            error!("Failed to update access control list. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case(None, &["sae_002", "sae_009"], &[]; "Disabled")]
+    #[test_case(Some(&["sae_002", "sae_003"]), &["sae_002", "sae_003"], &[]; "All granted")]
+    #[test_case(Some(&["sae_002"]), &["sae_002", "sae_003"], &["sae_003"]; "Partially granted")]
+    #[test_case(Some(&[]), &["sae_002"], &["sae_002"]; "Nothing granted")]
+    #[test_case(Some(&["sae_002"]), &["sae_009"], &["sae_009"]; "Unknown SAE")]
+    fn test_check_access(
+        allowed_slave_sae_ids: Option<&[&str]>,
+        slave_sae_ids: &[&str],
+        denied_slave_sae_ids: &[&str],
+    ) {
+        let allowed_slave_sae_ids: Option<HashSet<String>> =
+            allowed_slave_sae_ids.map(|sae_ids| {
+                sae_ids.iter().map(|sae_id| sae_id.to_string()).collect()
+            });
+        let slave_sae_ids: Vec<String> =
+            slave_sae_ids.iter().map(|sae_id| sae_id.to_string()).collect();
+
+        let expected = match denied_slave_sae_ids.is_empty() {
+            true => Ok(()),
+            false => Err(denied_slave_sae_ids
+                .iter()
+                .fold(Error::unauthorized(), |error, slave_sae_id| {
+                    error.with_detail(json!({
+                        "master_SAE_ID": "sae_001",
+                        "slave_SAE_ID": slave_sae_id
+                    }))
+                })
+                .to_string()),
+        };
+
+        assert_eq!(
+            check_access(
+                "sae_001",
+                &slave_sae_ids,
+                allowed_slave_sae_ids.as_ref()
+            )
+            .map_err(|e| e.to_string()),
+            expected
+        );
+    }
+}
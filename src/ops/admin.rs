@@ -0,0 +1,21 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::models::connection_info::ConnectionInfo;
+use actix_web::HttpRequest;
+use log::error;
+
+/// Checks that the request was issued by one of the configured
+/// administrators, identified by the common name of their certificate.
+pub fn authorize(request: &HttpRequest) -> Result<String, Error> {
+    let admin_id = ConnectionInfo::new(request)?.sae_id;
+
+    if !CONFIG.admin_ids.contains(&admin_id) {
+        error!("'{}' is not allowed to use the admin routes", admin_id);
+        return Err(Error::unauthorized());
+    }
+
+    Ok(admin_id)
+}
@@ -0,0 +1,416 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::common;
+use crate::config::CONFIG;
+use crate::converter;
+use crate::error::Error;
+use log::{error, info};
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+const MASTER_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Version of the key material stored in plaintext, before it was encrypted
+/// at rest.
+pub const PLAINTEXT_VERSION: i32 = 0;
+
+/// Master keys the key material in the 'keys' and 'key_pool' tables is
+/// encrypted with, by version.
+struct MasterKeys {
+    current_version: i32,
+    keys: HashMap<i32, Zeroizing<Vec<u8>>>,
+}
+
+impl MasterKeys {
+    fn get(&self, key_version: i32) -> Result<&[u8], Error> {
+        match self.keys.get(&key_version) {
+            Some(master_key) => Ok(master_key),
+            None => {
+                error!("Master key version {} is not configured", key_version);
+                Err(Error::internal_server_error())
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref MASTER_KEYS: RwLock<Arc<MasterKeys>> =
+        match load_configured_master_keys() {
+            Ok(master_keys) => RwLock::new(Arc::new(master_keys)),
+            Err(e) => {
+                error!("Invalid master keys configured: {}", e);
+                panic!("Invalid master keys configured");
+            }
+        };
+}
+
+/// Checks that the master keys can be loaded, and that one of them is used
+/// to encrypt the key material with.
+pub fn check_master_keys(
+    master_key_file: Option<&str>,
+    master_keys: &HashMap<String, String>,
+    master_key_version: i32,
+) -> Result<(), String> {
+    load_master_keys(master_key_file, master_keys, master_key_version)
+        .map(|_| ())
+}
+
+/// Reloads the master keys, such that keys added to the master key file are
+/// used without restarting the KME. The current keys are kept if the new ones
+/// are invalid.
+pub fn reload() -> Result<(), Error> {
+    let master_keys = match load_configured_master_keys() {
+        Ok(master_keys) => master_keys,
+        Err(e) => {
+            error!("Invalid master keys configured: {}", e);
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    common::store(&MASTER_KEYS, Arc::new(master_keys));
+
+    Ok(())
+}
+
+/// Version of the master key newly stored key material is encrypted with.
+pub fn current_version() -> i32 {
+    master_keys().current_version
+}
+
+/// Data the stored key material is authenticated along with, such that it
+/// cannot be moved to another row of the 'keys' table.
+pub fn associated_data(
+    key_id: &Uuid,
+    master_sae_id: &str,
+    slave_sae_id: &str,
+) -> Vec<u8> {
+    json!([key_id, master_sae_id, slave_sae_id]).to_string().into_bytes()
+}
+
+/// Data the key material of a pool block is authenticated along with, such
+/// that it cannot be moved to another block, link or table.
+pub fn pool_associated_data(
+    block_id: &Uuid,
+    source_kme_id: &str,
+    target_kme_id: &str,
+) -> Vec<u8> {
+    json!(["key_pool", block_id, source_kme_id, target_kme_id])
+        .to_string()
+        .into_bytes()
+}
+
+/// Encrypts base64 encoded key material with the given master key version,
+/// returning the base64 encoded nonce, ciphertext and tag.
+pub fn encrypt(
+    key_version: i32,
+    content: &str,
+    associated_data: &[u8],
+) -> Result<String, Error> {
+    let sealed = seal(
+        master_keys().get(key_version)?,
+        &converter::from_base64(content)?,
+        associated_data,
+    )?;
+
+    Ok(converter::to_base64(&sealed).to_string())
+}
+
+/// Decrypts key material stored with the given master key version, returning
+/// it base64 encoded.
+pub fn decrypt(
+    key_version: i32,
+    content: &str,
+    associated_data: &[u8],
+) -> Result<Zeroizing<String>, Error> {
+    if key_version == PLAINTEXT_VERSION {
+        return Ok(Zeroizing::new(content.to_string()));
+    }
+
+    let opened = open(
+        master_keys().get(key_version)?,
+        &converter::from_base64(content)?,
+        associated_data,
+    )?;
+
+    Ok(converter::to_base64(&opened))
+}
+
+fn master_keys() -> Arc<MasterKeys> {
+    common::load(&MASTER_KEYS)
+}
+
+fn seal(
+    master_key: &[u8],
+    plaintext: &[u8],
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    let mut tag = [0u8; TAG_LEN];
+
+    let ciphertext = rand_bytes(&mut nonce).and_then(|_| {
+        encrypt_aead(
+            Cipher::aes_256_gcm(),
+            master_key,
+            Some(&nonce),
+            associated_data,
+            plaintext,
+            &mut tag,
+        )
+    });
+
+    match ciphertext {
+        Ok(ciphertext) => Ok([&nonce[..], &ciphertext, &tag].concat()),
+        Err(e) => {
+            error!("Failed to encrypt key material. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+fn open(
+    master_key: &[u8],
+    sealed: &[u8],
+    associated_data: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        error!(
+            "Encrypted key material of {} bytes is truncated",
+            sealed.len()
+        );
+        return Err(Error::internal_server_error());
+    }
+
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+    match decrypt_aead(
+        Cipher::aes_256_gcm(),
+        master_key,
+        Some(nonce),
+        associated_data,
+        ciphertext,
+        tag,
+    ) {
+        Ok(plaintext) => Ok(Zeroizing::new(plaintext)),
+        Err(e) => {
+            error!("Failed to decrypt key material. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+/// Parses `version=key` entries, where the version is a positive integer and
+/// the key a base64 encoded 256 bit key.
+fn parse_master_keys<'a>(
+    entries: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<HashMap<i32, Zeroizing<Vec<u8>>>, String> {
+    let mut master_keys = HashMap::new();
+
+    for (version, key) in entries {
+        let version = match version.trim().parse::<i32>() {
+            Ok(version) if version > PLAINTEXT_VERSION => version,
+            _ => {
+                return Err(format!("invalid master key version '{}'", version))
+            }
+        };
+
+        let key = match converter::from_base64(key.trim()) {
+            Ok(key) if key.len() == MASTER_KEY_LEN => key,
+            _ => {
+                return Err(format!(
+                    "master key version {} must be {} base64 encoded bytes",
+                    version, MASTER_KEY_LEN
+                ))
+            }
+        };
+
+        if master_keys.insert(version, key).is_some() {
+            return Err(format!("master key version {} set twice", version));
+        }
+    }
+
+    Ok(master_keys)
+}
+
+/// Splits the lines of a master key file into `version=key` entries, skipping
+/// blank lines and comments.
+fn split_master_key_file(contents: &str) -> Result<Vec<(&str, &str)>, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once('=').ok_or_else(|| {
+                "master key file entries must be 'version=key'".to_string()
+            })
+        })
+        .collect()
+}
+
+fn load_configured_master_keys() -> Result<MasterKeys, String> {
+    load_master_keys(
+        CONFIG.master_key_file.as_deref(),
+        &CONFIG.master_keys,
+        CONFIG.master_key_version,
+    )
+}
+
+fn load_master_keys(
+    master_key_file: Option<&str>,
+    master_keys: &HashMap<String, String>,
+    master_key_version: i32,
+) -> Result<MasterKeys, String> {
+    let contents = match master_key_file {
+        Some(master_key_file) => {
+            fs::read_to_string(master_key_file).map_err(|e| {
+                format!("cannot read '{}': {:?}", master_key_file, e)
+            })?
+        }
+        None => String::new(),
+    };
+
+    let keys = parse_master_keys(
+        split_master_key_file(&contents)?.into_iter().chain(
+            master_keys
+                .iter()
+                .map(|(version, key)| (version.as_str(), key.as_str())),
+        ),
+    )?;
+
+    // The highest version is used unless another one is configured, such
+    // that adding a key is enough to start using it.
+    let current_version = match master_key_version {
+        PLAINTEXT_VERSION => keys
+            .keys()
+            .max()
+            .copied()
+            .ok_or("no master key to encrypt the key material with")?,
+        version if keys.contains_key(&version) => version,
+        version => {
+            return Err(format!(
+                "master key version {} is not configured",
+                version
+            ))
+        }
+    };
+
+    info!(
+        "Loaded {} master keys, encrypting with version {}",
+        keys.len(),
+        current_version
+    );
+
+    Ok(MasterKeys {
+        current_version,
+        keys,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    const MASTER_KEY: [u8; MASTER_KEY_LEN] = [0x42; MASTER_KEY_LEN];
+
+    #[test]
+    fn test_seal_and_open() {
+        let sealed = seal(&MASTER_KEY, b"key material", b"row").unwrap();
+
+        assert_eq!(sealed.len(), NONCE_LEN + 12 + TAG_LEN);
+        assert_eq!(
+            *open(&MASTER_KEY, &sealed, b"row").unwrap(),
+            b"key material"
+        );
+    }
+
+    #[test]
+    fn test_open_rejects_tampering() {
+        let mut sealed = seal(&MASTER_KEY, b"key material", b"row").unwrap();
+
+        assert!(open(&MASTER_KEY, &sealed, b"other row").is_err());
+        assert!(open(&[0x24; MASTER_KEY_LEN], &sealed, b"row").is_err());
+        assert!(open(&MASTER_KEY, &sealed[..NONCE_LEN], b"row").is_err());
+
+        sealed[NONCE_LEN] ^= 0x01;
+        assert!(open(&MASTER_KEY, &sealed, b"row").is_err());
+    }
+
+    #[test_case("1", true; "Valid")]
+    #[test_case("0", false; "Plaintext version")]
+    #[test_case("-1", false; "Negative version")]
+    #[test_case("one", false; "Invalid version")]
+    fn test_parse_master_key_version(version: &str, is_ok: bool) {
+        let key = converter::to_base64(&MASTER_KEY);
+
+        assert_eq!(parse_master_keys([(version, key.as_str())]).is_ok(), is_ok);
+    }
+
+    #[test]
+    fn test_parse_master_keys_rejects_invalid_keys() {
+        let short_key = converter::to_base64(&MASTER_KEY[1..]);
+        let key = converter::to_base64(&MASTER_KEY);
+
+        assert!(parse_master_keys([("1", short_key.as_str())]).is_err());
+        assert!(parse_master_keys([("1", "not base64")]).is_err());
+        assert!(
+            parse_master_keys([("1", key.as_str()), ("1", key.as_str())])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_split_master_key_file() {
+        let contents = "# Rotated on 2023-01-13\n1=AAAA\n\n 2 = BBBB \n";
+
+        assert_eq!(
+            split_master_key_file(contents).unwrap(),
+            vec![("1", "AAAA"), ("2 ", " BBBB")]
+        );
+        assert!(split_master_key_file("AAAA").is_err());
+    }
+
+    #[test_case(PLAINTEXT_VERSION, Some(2); "Highest version")]
+    #[test_case(1, Some(1); "Configured version")]
+    #[test_case(3, None; "Missing version")]
+    fn test_load_master_keys_version(
+        master_key_version: i32,
+        current_version: Option<i32>,
+    ) {
+        let key = converter::to_base64(&MASTER_KEY).to_string();
+        let master_keys = HashMap::from([
+            ("1".to_string(), key.clone()),
+            ("2".to_string(), key),
+        ]);
+
+        assert_eq!(
+            load_master_keys(None, &master_keys, master_key_version)
+                .ok()
+                .map(|master_keys| master_keys.current_version),
+            current_version
+        );
+    }
+
+    #[test]
+    fn test_load_master_keys_requires_a_key() {
+        assert!(
+            load_master_keys(None, &HashMap::new(), PLAINTEXT_VERSION).is_err()
+        );
+        assert!(load_master_keys(
+            Some("/nonexistent/master.key"),
+            &HashMap::new(),
+            PLAINTEXT_VERSION
+        )
+        .is_err());
+    }
+}
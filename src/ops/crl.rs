@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use log::{error, info};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslContextBuilder, SslFiletype};
+use openssl::x509::store::X509Lookup;
+use openssl::x509::verify::X509VerifyFlags;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Certificates of the peer's chain checked against the revocation lists.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrlCheck {
+    /// Only the peer certificate.
+    Leaf,
+    /// Every certificate of the chain.
+    Chain,
+}
+
+pub fn parse_check(crl_check: &str) -> Result<CrlCheck, String> {
+    match crl_check {
+        "leaf" => Ok(CrlCheck::Leaf),
+        "chain" => Ok(CrlCheck::Chain),
+        _ => Err(format!(
+            "unknown revocation check '{}', expected 'leaf' or 'chain'",
+            crl_check
+        )),
+    }
+}
+
+/// Whether certificate revocation lists are configured, in which case peer
+/// certificates issued by a CA without one are rejected.
+fn is_enabled() -> bool {
+    !CONFIG.crl_files.is_empty() || CONFIG.crl_dir.is_some()
+}
+
+/// Loads the configured certificate revocation lists into the context's
+/// certificate store, such that revoked peer certificates are rejected during
+/// the handshake.
+pub fn load(builder: &mut SslContextBuilder) -> Result<(), ErrorStack> {
+    if !is_enabled() {
+        return Ok(());
+    }
+
+    let lookup = builder.cert_store_mut().add_lookup(X509Lookup::file())?;
+
+    // NOTE: An unreadable directory contributes no revocation list, hence
+    // the peers it would have covered are rejected rather than trusted.
+    let crl_files = match files() {
+        Ok(crl_files) => crl_files,
+        Err(e) => {
+            error!(
+                "Failed to read revocation list directory '{}'. Error: {:?}",
+                CONFIG.crl_dir.as_deref().unwrap_or_default(),
+                e
+            );
+            CONFIG.crl_files.iter().map(PathBuf::from).collect()
+        }
+    };
+
+    let mut crl_count = 0;
+    for crl_file in crl_files {
+        crl_count += lookup.load_crl_file(&crl_file, SslFiletype::PEM)?;
+    }
+
+    let mut flags = X509VerifyFlags::CRL_CHECK;
+    if parse_check(&CONFIG.crl_check) == Ok(CrlCheck::Chain) {
+        flags |= X509VerifyFlags::CRL_CHECK_ALL;
+    }
+    builder.cert_store_mut().set_flags(flags)?;
+
+    info!(
+        "Checking certificates against {} revocation lists",
+        crl_count
+    );
+
+    Ok(())
+}
+
+/// Returns the configured revocation list files, followed by the files of the
+/// configured directory, in name order.
+pub fn files() -> io::Result<Vec<PathBuf>> {
+    list_files(&CONFIG.crl_files, CONFIG.crl_dir.as_deref())
+}
+
+fn list_files(
+    crl_files: &[String],
+    crl_dir: Option<&str>,
+) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = crl_files.iter().map(PathBuf::from).collect();
+
+    if let Some(crl_dir) = crl_dir {
+        let mut dir_files = fs::read_dir(crl_dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<PathBuf>>>()?;
+        dir_files.retain(|path| path.is_file());
+        dir_files.sort();
+        files.extend(dir_files);
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::env;
+    use test_case::test_case;
+    use uuid::Uuid;
+
+    #[test_case("leaf", Some(CrlCheck::Leaf); "Leaf")]
+    #[test_case("chain", Some(CrlCheck::Chain); "Chain")]
+    #[test_case("all", None; "Unknown check")]
+    #[test_case("Leaf", None; "Wrong case")]
+    fn test_parse_check(crl_check: &str, expected: Option<CrlCheck>) {
+        assert_eq!(parse_check(crl_check).ok(), expected);
+    }
+
+    #[test]
+    fn test_list_files() {
+        let crl_dir = env::temp_dir().join(format!("crl_{}", Uuid::new_v4()));
+        fs::create_dir_all(crl_dir.join("archive")).unwrap();
+        for name in ["b.crl", "a.crl"] {
+            fs::write(crl_dir.join(name), "").unwrap();
+        }
+
+        let files = list_files(
+            &["root.crl".to_string()],
+            Some(crl_dir.to_str().unwrap()),
+        );
+        fs::remove_dir_all(&crl_dir).unwrap();
+
+        assert_eq!(
+            files.unwrap(),
+            vec![
+                PathBuf::from("root.crl"),
+                crl_dir.join("a.crl"),
+                crl_dir.join("b.crl"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_files_without_directory() {
+        assert_eq!(
+            list_files(&["root.crl".to_string()], None).unwrap(),
+            vec![PathBuf::from("root.crl")]
+        );
+    }
+
+    #[test]
+    fn test_list_files_of_missing_directory() {
+        let crl_dir = env::temp_dir().join(format!("crl_{}", Uuid::new_v4()));
+
+        assert!(list_files(&[], Some(crl_dir.to_str().unwrap())).is_err());
+    }
+}
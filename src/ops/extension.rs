@@ -0,0 +1,266 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::error::Error;
+use log::{debug, warn};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+pub type ExtensionParams = Vec<Map<String, Value>>;
+
+/// Extension data attached to the keys generated for a request.
+#[derive(Default, Debug, PartialEq)]
+pub struct KeyExtensions {
+    pub key_id: Map<String, Value>,
+    pub key: Map<String, Value>,
+    pub container: Map<String, Value>,
+}
+
+impl KeyExtensions {
+    pub fn key_id_value(&self) -> Option<Value> {
+        Self::to_value(&self.key_id)
+    }
+
+    pub fn key_value(&self) -> Option<Value> {
+        Self::to_value(&self.key)
+    }
+
+    pub fn container_value(&self) -> Option<Value> {
+        Self::to_value(&self.container)
+    }
+
+    fn to_value(extension: &Map<String, Value>) -> Option<Value> {
+        match extension.is_empty() {
+            true => None,
+            false => Some(Value::Object(extension.clone())),
+        }
+    }
+}
+
+/// Facts about the key request the extensions may depend on.
+#[derive(Default, Debug)]
+pub struct ExtensionContext {
+    /// Whether the keys are relayed by intermediate KMEs, rather than shared
+    /// over a direct link.
+    pub is_relayed: bool,
+}
+
+/// Handles a single extension parameter of the Key Request data format.
+pub trait ExtensionHandler: Send + Sync {
+    /// Name of the extension parameter, as it appears in the request.
+    fn name(&self) -> &'static str;
+
+    /// Processes the value supplied for the extension, returning an error if
+    /// the KME cannot honour it. Any data that should accompany the generated
+    /// keys is added to `extensions`.
+    fn handle(
+        &self,
+        value: &Value,
+        context: &ExtensionContext,
+        extensions: &mut KeyExtensions,
+    ) -> Result<(), Error>;
+}
+
+pub struct ExtensionRegistry {
+    handlers: HashMap<&'static str, Box<dyn ExtensionHandler>>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) {
+        self.handlers.insert(handler.name(), handler);
+    }
+
+    /// Processes the extensions of a key request.
+    ///
+    /// All mandatory extensions must be supported by a registered handler,
+    /// otherwise the request is rejected listing the unsupported ones.
+    /// Unsupported optional extensions are ignored.
+    pub fn process(
+        &self,
+        mandatory: &[Map<String, Value>],
+        optional: &[Map<String, Value>],
+        context: &ExtensionContext,
+    ) -> Result<KeyExtensions, Error> {
+        let mut extensions = KeyExtensions::default();
+
+        let unsupported: Vec<&str> = mandatory
+            .iter()
+            .flat_map(|extension| extension.keys())
+            .filter(|name| !self.handlers.contains_key(name.as_str()))
+            .map(|name| name.as_str())
+            .collect();
+
+        if !unsupported.is_empty() {
+            return Err(unsupported.into_iter().fold(
+                Error::bad_request("Unsupported mandatory extension(s)"),
+                |error, name| error.with_detail(json!({ "extension": name })),
+            ));
+        }
+
+        for (name, value) in mandatory.iter().flatten() {
+            self.handlers[name.as_str()].handle(
+                value,
+                context,
+                &mut extensions,
+            )?;
+        }
+
+        for (name, value) in optional.iter().flatten() {
+            match self.handlers.get(name.as_str()) {
+                Some(handler) => {
+                    if let Err(e) =
+                        handler.handle(value, context, &mut extensions)
+                    {
+                        warn!("Ignoring optional extension '{}': {}", name, e);
+                    }
+                }
+                None => debug!("Ignoring unsupported extension '{}'", name),
+            }
+        }
+
+        Ok(extensions)
+    }
+}
+
+/// Route over which the keys are delivered to the slave SAE, either 'direct'
+/// over the link to its KME or 'indirect' through trusted nodes. The request
+/// is rejected if the keys would take the other route.
+struct RouteType;
+
+impl ExtensionHandler for RouteType {
+    fn name(&self) -> &'static str {
+        "route_type"
+    }
+
+    fn handle(
+        &self,
+        value: &Value,
+        context: &ExtensionContext,
+        _extensions: &mut KeyExtensions,
+    ) -> Result<(), Error> {
+        match (value.as_str(), context.is_relayed) {
+            (Some("direct"), false) | (Some("indirect"), true) => Ok(()),
+            (Some("direct"), true) | (Some("indirect"), false) => {
+                Err(Error::bad_request("Requested route not available")
+                    .with_detail(json!({ "extension": self.name() })))
+            }
+            _ => Err(Error::bad_request("Unsupported extension value")
+                .with_detail(json!({ "extension": self.name() }))),
+        }
+    }
+}
+
+/// Algorithm the keys are intended to be used with. The hint is returned to
+/// both the master and slave SAEs in the key extension.
+struct Algorithm;
+
+impl ExtensionHandler for Algorithm {
+    fn name(&self) -> &'static str {
+        "algorithm"
+    }
+
+    fn handle(
+        &self,
+        value: &Value,
+        _context: &ExtensionContext,
+        extensions: &mut KeyExtensions,
+    ) -> Result<(), Error> {
+        match value.as_str() {
+            Some(algorithm) if !algorithm.trim().is_empty() => {
+                extensions.key.insert(self.name().to_string(), value.clone());
+                Ok(())
+            }
+            _ => Err(Error::bad_request("Invalid extension value")
+                .with_detail(json!({ "extension": self.name() }))),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref EXTENSIONS: ExtensionRegistry = {
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(RouteType));
+        registry.register(Box::new(Algorithm));
+        registry
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    fn to_params(value: Value) -> ExtensionParams {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test_case(true, json!([]); "No extensions")]
+    #[test_case(true, json!([{"route_type": "direct"}]); "Supported value")]
+    #[test_case(false, json!([{"route_type": "indirect"}]); "Unavailable route")]
+    #[test_case(false, json!([{"route_type": "satellite"}]); "Unsupported value")]
+    #[test_case(false, json!([{"abc_transfer_method": "qkd"}]); "Unsupported extension")]
+    #[test_case(false, json!([{"route_type": "direct", "abc_max_age": 30}]); "Partially supported")]
+    fn test_mandatory_extensions(is_ok: bool, mandatory: Value) {
+        assert_eq!(
+            EXTENSIONS
+                .process(
+                    &to_params(mandatory),
+                    &[],
+                    &ExtensionContext::default()
+                )
+                .is_ok(),
+            is_ok
+        );
+    }
+
+    #[test_case(true, "indirect"; "Relayed keys")]
+    #[test_case(false, "direct"; "Direct route requested")]
+    fn test_relayed_route_type(is_ok: bool, route_type: &str) {
+        let context = ExtensionContext { is_relayed: true };
+
+        assert_eq!(
+            EXTENSIONS
+                .process(
+                    &to_params(json!([{ "route_type": route_type }])),
+                    &[],
+                    &context
+                )
+                .is_ok(),
+            is_ok
+        );
+    }
+
+    #[test_case(json!([{"route_type": "direct"}]); "Supported value")]
+    #[test_case(json!([{"route_type": "indirect"}]); "Unavailable route")]
+    #[test_case(json!([{"abc_transfer_method": "qkd"}]); "Unsupported extension")]
+    fn test_optional_extensions_are_never_rejected(optional: Value) {
+        assert!(EXTENSIONS
+            .process(&[], &to_params(optional), &ExtensionContext::default())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_algorithm_added_to_key_extension() {
+        let extensions = EXTENSIONS
+            .process(
+                &[],
+                &to_params(json!([{"algorithm": "AES-256"}])),
+                &ExtensionContext::default(),
+            )
+            .unwrap();
+
+        assert_eq!(extensions.key_id_value(), None);
+        assert_eq!(
+            extensions.key_value(),
+            Some(json!({"algorithm": "AES-256"}))
+        );
+        assert_eq!(extensions.container_value(), None);
+    }
+}
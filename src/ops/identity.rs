@@ -0,0 +1,297 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::ops::server;
+use log::error;
+use openssl::hash::MessageDigest;
+use openssl::x509::{GeneralNameRef, X509Ref};
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Part of the client certificate the SAE ID is taken from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    /// First common name of the subject.
+    CommonName,
+    /// Subject alternative names of the given type, the first one matching
+    /// the pattern being used.
+    SubjectAltName(SanType),
+    /// Subject formatted as 'C=GB, O=Merqury, CN=sae_001', which requires a
+    /// pattern.
+    DistinguishedName,
+    /// SHA-256 fingerprint of the certificate, looked up in a table.
+    Fingerprint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SanType {
+    Uri,
+    Dns,
+    Email,
+}
+
+impl Source {
+    fn parse(source: &str) -> Result<Self, String> {
+        match source {
+            "cn" => Ok(Source::CommonName),
+            "san_uri" => Ok(Source::SubjectAltName(SanType::Uri)),
+            "san_dns" => Ok(Source::SubjectAltName(SanType::Dns)),
+            "san_email" => Ok(Source::SubjectAltName(SanType::Email)),
+            "dn" => Ok(Source::DistinguishedName),
+            "fingerprint" => Ok(Source::Fingerprint),
+            _ => Err(format!(
+                "unknown SAE ID source '{}', expected 'cn', 'san_uri', \
+                 'san_dns', 'san_email', 'dn' or 'fingerprint'",
+                source
+            )),
+        }
+    }
+}
+
+/// How the SAE ID is extracted from the client certificate.
+struct IdentityMapping {
+    source: Source,
+    pattern: Option<Regex>,
+    fingerprints: HashMap<String, String>,
+}
+
+impl IdentityMapping {
+    fn new(
+        source: &str,
+        pattern: Option<&str>,
+        fingerprints: &HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let source = Source::parse(source)?;
+
+        let pattern = match pattern {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| {
+                format!("invalid SAE ID pattern '{}': {}", pattern, e)
+            })?),
+            None => None,
+        };
+
+        match (source, &pattern) {
+            (Source::DistinguishedName, None) => {
+                return Err("the 'dn' SAE ID source requires a pattern".into())
+            }
+            (Source::Fingerprint, Some(_)) => {
+                return Err(
+                    "the 'fingerprint' SAE ID source takes no pattern".into()
+                )
+            }
+            (Source::Fingerprint, None) if fingerprints.is_empty() => {
+                return Err(
+                    "the 'fingerprint' SAE ID source requires fingerprints"
+                        .into(),
+                )
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            source,
+            pattern,
+            fingerprints: fingerprints
+                .iter()
+                .map(|(fingerprint, sae_id)| {
+                    (normalize_fingerprint(fingerprint), sae_id.clone())
+                })
+                .collect(),
+        })
+    }
+
+    fn sae_id(&self, cert: &X509Ref) -> Option<String> {
+        match self.source {
+            Source::CommonName => {
+                self.apply_pattern(&server::common_name(cert)?)
+            }
+            Source::SubjectAltName(san_type) => cert
+                .subject_alt_names()?
+                .iter()
+                .filter_map(|name| san_value(name, san_type))
+                .find_map(|value| self.apply_pattern(value)),
+            Source::DistinguishedName => self.apply_pattern(
+                &server::distinguished_name(cert.subject_name()),
+            ),
+            Source::Fingerprint => {
+                self.fingerprints.get(&fingerprint(cert)?).cloned()
+            }
+        }
+    }
+
+    /// Returns the first capture group of the pattern, or the whole match
+    /// when it has none. Values are used as they are when no pattern is
+    /// configured.
+    fn apply_pattern(&self, value: &str) -> Option<String> {
+        let sae_id = match &self.pattern {
+            Some(pattern) => {
+                let captures = pattern.captures(value)?;
+                captures.get(1).or_else(|| captures.get(0))?.as_str()
+            }
+            None => value,
+        };
+
+        match sae_id.is_empty() {
+            true => None,
+            false => Some(sae_id.to_string()),
+        }
+    }
+}
+
+lazy_static! {
+    static ref IDENTITY_MAPPING: IdentityMapping = match IdentityMapping::new(
+        &CONFIG.sae_id_source,
+        CONFIG.sae_id_pattern.as_deref(),
+        &CONFIG.sae_fingerprints,
+    ) {
+        Ok(identity_mapping) => identity_mapping,
+        Err(e) => {
+            error!("Invalid SAE identity mapping configured: {}", e);
+            panic!("Invalid SAE identity mapping configured");
+        }
+    };
+}
+
+/// Checks that the SAE ID can be extracted the configured way.
+pub fn check_mapping(
+    source: &str,
+    pattern: Option<&str>,
+    fingerprints: &HashMap<String, String>,
+) -> Result<(), String> {
+    IdentityMapping::new(source, pattern, fingerprints).map(|_| ())
+}
+
+/// Returns the ID of the SAE, or peer KME, the certificate was issued to, as
+/// configured through the SAE ID source.
+pub fn sae_id(cert: &X509Ref) -> Option<String> {
+    IDENTITY_MAPPING.sae_id(cert)
+}
+
+fn san_value(name: &GeneralNameRef, san_type: SanType) -> Option<&str> {
+    match san_type {
+        SanType::Uri => name.uri(),
+        SanType::Dns => name.dnsname(),
+        SanType::Email => name.email(),
+    }
+}
+
+/// Returns the SHA-256 fingerprint of the certificate, as lowercase hex.
+fn fingerprint(cert: &X509Ref) -> Option<String> {
+    match cert.digest(MessageDigest::sha256()) {
+        Ok(digest) => {
+            Some(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+        }
+        Err(e) => {
+            error!("Failed to compute certificate fingerprint: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Accepts fingerprints as printed by 'openssl x509 -fingerprint', i.e. with
+/// colons and in uppercase.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|c| *c != ':').collect::<String>().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509Name, X509};
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    fn build_cert() -> X509 {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Merqury").unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "sae_001").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("kme.example.com")
+            .uri("https://example.com/saes")
+            .uri("urn:etsi:qkd:sae:sae_123")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        builder.build()
+    }
+
+    #[test_case("cn", None, Some("sae_001"); "Common name")]
+    #[test_case("cn", Some("^sae_(\\d+)$"), Some("001"); "Common name pattern")]
+    #[test_case("cn", Some("^kme_"), None; "Common name mismatch")]
+    #[test_case("san_uri", None, Some("https://example.com/saes"); "URI")]
+    #[test_case(
+        "san_uri", Some("^urn:etsi:qkd:sae:(.+)$"), Some("sae_123");
+        "URI pattern"
+    )]
+    #[test_case("san_dns", None, Some("kme.example.com"); "DNS name")]
+    #[test_case("san_email", None, None; "Missing email")]
+    #[test_case("dn", Some("O=Merqury, CN=(\\w+)"), Some("sae_001"); "DN")]
+    fn test_sae_id(
+        source: &str,
+        pattern: Option<&str>,
+        expected_sae_id: Option<&str>,
+    ) {
+        let identity_mapping =
+            IdentityMapping::new(source, pattern, &HashMap::new()).unwrap();
+
+        assert_eq!(
+            identity_mapping.sae_id(&build_cert()).as_deref(),
+            expected_sae_id
+        );
+    }
+
+    #[test]
+    fn test_sae_id_from_fingerprint() {
+        let cert = build_cert();
+        let fingerprint = cert
+            .digest(MessageDigest::sha256())
+            .unwrap()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<String>>()
+            .join(":");
+
+        let identity_mapping = IdentityMapping::new(
+            "fingerprint",
+            None,
+            &HashMap::from([(fingerprint, "sae_042".to_string())]),
+        )
+        .unwrap();
+        assert_eq!(identity_mapping.sae_id(&cert).as_deref(), Some("sae_042"));
+
+        let identity_mapping = IdentityMapping::new(
+            "fingerprint",
+            None,
+            &HashMap::from([("00:11".to_string(), "sae_042".to_string())]),
+        )
+        .unwrap();
+        assert_eq!(identity_mapping.sae_id(&cert), None);
+    }
+
+    #[test_case("subject", None; "Unknown source")]
+    #[test_case("cn", Some("(sae"); "Invalid pattern")]
+    #[test_case("dn", None; "DN without pattern")]
+    #[test_case("fingerprint", None; "No fingerprints")]
+    fn test_invalid_identity_mapping(source: &str, pattern: Option<&str>) {
+        assert!(IdentityMapping::new(source, pattern, &HashMap::new()).is_err());
+    }
+}
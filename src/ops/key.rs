@@ -1,14 +1,20 @@
 // SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use crate::models::key::NewKey;
-use crate::{converter, db};
+use crate::config::CONFIG;
+use crate::models::key::{KeyContainer, NewKey, NewKeys};
+use crate::models::policy::KeyPolicy;
+use crate::ops::key_source::KeySource;
+use crate::{converter, db, ops};
 use crate::{error::Error, models::key::Key};
-use actix_web::http::StatusCode;
-use log::error;
-use rand::prelude::*;
-use sqlx::PgPool;
+use actix_web::{http::StatusCode, rt};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{error, info};
+use serde_json::{json, Map, Value};
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 pub fn validate_key_size(key_size_bits: i32) -> Result<(), Error> {
     if key_size_bits <= 0 {
@@ -39,7 +45,8 @@ pub fn validate_num_keys(num_keys: i32) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn generate_random_keys(
+pub fn generate_keys(
+    key_source: &dyn KeySource,
     key_size_bits: i32,
     num_keys: i32,
 ) -> Result<Vec<Key>, Error> {
@@ -57,7 +64,9 @@ pub fn generate_random_keys(
     for _ in 0..num_keys {
         keys.push(Key {
             id: Uuid::new_v4(),
-            content: generate_random_key(key_size_bits)?,
+            id_extension: None,
+            content: generate_key(key_source, key_size_bits)?,
+            extension: None,
             size: key_size_bits,
         });
     }
@@ -65,12 +74,18 @@ pub fn generate_random_keys(
     Ok(keys)
 }
 
-fn generate_random_key(key_size_bits: i32) -> Result<String, Error> {
-    let key_data = generate_random_key_bytes(key_size_bits)?;
+fn generate_key(
+    key_source: &dyn KeySource,
+    key_size_bits: i32,
+) -> Result<Zeroizing<String>, Error> {
+    let key_data = generate_key_bytes(key_source, key_size_bits)?;
     Ok(converter::to_base64(&key_data))
 }
 
-fn generate_random_key_bytes(key_size_bits: i32) -> Result<Vec<u8>, Error> {
+fn generate_key_bytes(
+    key_source: &dyn KeySource,
+    key_size_bits: i32,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
     if key_size_bits % 8 != 0 || key_size_bits == 0 {
         return Err(Error::new(
             StatusCode::BAD_REQUEST,
@@ -86,130 +101,235 @@ fn generate_random_key_bytes(key_size_bits: i32) -> Result<Vec<u8>, Error> {
         }
     };
 
-    let mut key_material = vec![0; key_size_bytes];
-    thread_rng().fill_bytes(&mut key_material);
+    let mut key_material = Zeroizing::new(vec![0; key_size_bytes]);
+    key_source.fill_bytes(&mut key_material)?;
     Ok(key_material)
 }
 
+/// Returns when keys shared under the given policies expire, honouring the
+/// shortest time-to-live among them.
+pub fn expiry(policies: &[KeyPolicy]) -> Option<DateTime<Utc>> {
+    let key_ttl_secs = policies
+        .iter()
+        .map(|policy| policy.key_ttl_secs)
+        .filter(|key_ttl_secs| *key_ttl_secs > 0)
+        .min()?;
+
+    // Time-to-live values too large to be represented never expire.
+    let key_ttl = TimeDelta::try_seconds(i64::try_from(key_ttl_secs).ok()?)?;
+    Utc::now().checked_add_signed(key_ttl)
+}
+
 pub async fn save_keys(
-    keys: &[Key],
+    connection: &mut PgConnection,
+    key_container: &KeyContainer,
     master_sae_id: &str,
     slave_sae_ids: &[String],
+    expires_at: Option<DateTime<Utc>>,
 ) -> Result<(), Error> {
-    let num_rows_to_insert = keys.len() * slave_sae_ids.len();
+    let mut keys_to_insert = NewKeys::default();
+    let key_version = ops::cipher::current_version();
 
-    let mut keys_to_insert: Vec<NewKey> =
-        Vec::with_capacity(num_rows_to_insert);
-
-    for key in keys {
+    for key in &key_container.keys {
         for slave_sae_id in slave_sae_ids {
+            // Every slave SAE gets its own copy of the key, encrypted under a
+            // fresh nonce and bound to its row.
+            let content = ops::cipher::encrypt(
+                key_version,
+                &key.content,
+                &ops::cipher::associated_data(
+                    &key.id,
+                    master_sae_id,
+                    slave_sae_id,
+                ),
+            )?;
+
             keys_to_insert.push(NewKey {
                 id: key.id,
                 master_sae_id: master_sae_id.to_string(),
                 slave_sae_id: slave_sae_id.clone(),
                 size: key.size,
-                content: key.content.clone(),
+                content,
+                id_extension: key.id_extension.clone(),
+                extension: key.extension.clone(),
+                container_extension: key_container
+                    .key_container_extension
+                    .clone(),
             });
         }
     }
 
-    let pool = &db::establish_connection().await?;
-    let mut num_inserted_rows: u64 = 0;
-    for key in keys_to_insert {
-        let result = match sqlx::query_file!(
-            "sql/insert_keys.sql",
-            key.id,
-            key.master_sae_id,
-            key.slave_sae_id,
-            key.size,
-            key.content,
-        )
-        .execute(pool)
-        .await
-        {
-            Ok(res) => res,
-            Err(e) => {
-                error!("Failed to save records to db: {:?}", e);
-                return Err(Error::internal_server_error());
-            }
-        };
-        num_inserted_rows += result.rows_affected();
+    let result = match sqlx::query_file!(
+        "sql/insert_keys.sql",
+        &keys_to_insert.ids,
+        &keys_to_insert.master_sae_ids,
+        &keys_to_insert.slave_sae_ids,
+        &keys_to_insert.sizes,
+        &keys_to_insert.contents,
+        &keys_to_insert.id_extensions as &[Option<Value>],
+        &keys_to_insert.extensions as &[Option<Value>],
+        &keys_to_insert.container_extensions as &[Option<Value>],
+        expires_at,
+        key_version,
+    )
+    .execute(&mut *connection)
+    .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            error!("Failed to save records to db: {:?}", e);
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    // The caller is expected to roll back the enclosing transaction on
+    // error, such that no slave SAE is left with a partial set of keys.
+    if result.rows_affected() != keys_to_insert.len() as u64 {
+        error!(
+            "Expected to insert {} rows but {} were inserted",
+            keys_to_insert.len(),
+            result.rows_affected()
+        );
+        return Err(Error::internal_server_error());
     }
-    assert_eq!(num_rows_to_insert as u64, num_inserted_rows);
+
     Ok(())
 }
 
+/// Retrieves, and consumes, all the requested keys using a single query.
+///
+/// If any of the keys cannot be delivered to the slave SAE, none of them are
+/// consumed and the returned error lists every offending key ID.
 pub async fn get_multiple_keys(
+    pool: &PgPool,
     key_ids: &[uuid::Uuid],
     master_sae_id: &str,
     slave_sae_id: &str,
-) -> Result<Vec<Key>, Error> {
-    let mut result: Vec<Key> = Vec::new();
-
-    let pool = &db::establish_connection().await?;
-
-    for key_id in key_ids {
-        result.push(
-            retrieve_key_from_db(key_id, master_sae_id, slave_sae_id, pool)
-                .await?,
-        );
-    }
-
-    Ok(result)
-}
+) -> Result<KeyContainer, Error> {
+    let mut transaction = db::begin(pool).await?;
 
-async fn retrieve_key_from_db(
-    key_id: &uuid::Uuid,
-    master_sae_id: &str,
-    slave_sae_id: &str,
-    pool: &PgPool,
-) -> Result<Key, Error> {
-    let num_keys_with_master_sae_id = match sqlx::query_file!(
-        "sql/count_keys.sql",
-        key_id,
+    let rows = match sqlx::query_file!(
+        "sql/retrieve_keys.sql",
+        key_ids,
         master_sae_id,
+        slave_sae_id,
     )
-    .fetch_one(pool)
+    .fetch_all(&mut *transaction)
     .await
     {
-        Ok(res) => res.count,
+        Ok(rows) => rows,
         Err(e) => {
-            error!(
-                "Failed to count the number of keys with a specific master_sae_id. Error: {:?}",
-                e
-            );
+            error!("Failed to retrieve keys. Error: {:?}", e);
             return Err(Error::internal_server_error());
         }
     };
 
-    let retrieval_result = match sqlx::query_file_as!(
-        Key,
-        "sql/retrieve_key.sql",
-        key_id,
-        master_sae_id,
-        slave_sae_id,
-    )
-    .fetch_optional(pool)
-    .await
-    {
-        Ok(res) => res,
-        Err(e) => {
-            error!("Failed to retrieve key. Error: {:?}", e);
-            return Err(Error::internal_server_error());
+    let mut keys: Vec<Key> = Vec::with_capacity(rows.len());
+    let mut container_extension = Map::new();
+    let mut failures: Vec<Value> = Vec::new();
+    let mut is_unauthorized = false;
+
+    for row in rows {
+        let (content, key_version, size) =
+            match (row.content, row.key_version, row.size) {
+                (Some(content), Some(key_version), Some(size)) => {
+                    (content, key_version, size)
+                }
+                _ => {
+                    let reason = if row.is_expired {
+                        "Key has expired"
+                    } else if row.exists_for_slave {
+                        "Key has already been retrieved"
+                    } else if row.exists_for_master {
+                        is_unauthorized = true;
+                        "Unauthorized"
+                    } else {
+                        "Key not found"
+                    };
+
+                    failures
+                        .push(json!({ "key_ID": row.id, "message": reason }));
+                    continue;
+                }
+            };
+
+        // The keys may have been generated by different requests, hence their
+        // container extensions are merged into a single one.
+        if let Some(Value::Object(extension)) = row.key_container_extension {
+            container_extension.extend(extension);
         }
-    };
 
-    match retrieval_result {
-        Some(retrieved_key) => Ok(retrieved_key),
-        None => {
-            if num_keys_with_master_sae_id > 0 {
-                Err(Error::unauthorized())
-            } else {
-                Err(Error::new(
-                    StatusCode::BAD_REQUEST,
-                    format!("Key {} not found", key_id).as_str(),
-                ))
+        keys.push(Key {
+            id: row.id,
+            id_extension: row.key_id_extension,
+            content: ops::cipher::decrypt(
+                key_version,
+                &content,
+                &ops::cipher::associated_data(
+                    &row.id,
+                    master_sae_id,
+                    slave_sae_id,
+                ),
+            )?,
+            extension: row.key_extension,
+            size,
+        });
+    }
+
+    // Dropping the transaction without committing it rolls back the
+    // consumption of the keys that were found.
+    if !failures.is_empty() {
+        let error = match is_unauthorized {
+            true => Error::unauthorized(),
+            false => Error::new(
+                StatusCode::BAD_REQUEST,
+                "One or more keys could not be retrieved",
+            ),
+        };
+
+        return Err(failures
+            .into_iter()
+            .fold(error, |error, detail| error.with_detail(detail)));
+    }
+
+    db::commit(transaction).await?;
+
+    Ok(KeyContainer {
+        keys,
+        key_container_extension: match container_extension.is_empty() {
+            true => None,
+            false => Some(Value::Object(container_extension)),
+        },
+    })
+}
+
+/// Periodically deletes the keys that expired before being retrieved.
+pub async fn run_purger(pool: PgPool) {
+    let mut interval =
+        rt::time::interval(Duration::from_secs(CONFIG.key_purge_interval_secs));
+
+    info!("Expired key purger started");
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = purge_expired_keys(&pool).await {
+            error!("Failed to purge expired keys: {}", e);
+        }
+    }
+}
+
+async fn purge_expired_keys(pool: &PgPool) -> Result<(), Error> {
+    match sqlx::query_file!("sql/purge_expired_keys.sql").execute(pool).await {
+        Ok(res) => {
+            if res.rows_affected() > 0 {
+                info!("Purged {} expired keys", res.rows_affected());
             }
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to delete expired keys. Error: {:?}", e);
+            Err(Error::internal_server_error())
         }
     }
 }
@@ -217,6 +337,7 @@ async fn retrieve_key_from_db(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ops::key_source::RandomKeySource;
     use pretty_assertions::assert_eq;
     use test_case::test_case;
 
@@ -226,14 +347,17 @@ mod tests {
     #[test_case(false, 17; "Positive value, non-divisible by 8")]
     #[test_case(true, 16; "Positive value, divisible by 8")]
     fn test_key_size_validation(is_ok: bool, key_size_bits: i32) {
-        assert_eq!(generate_random_keys(key_size_bits, 1).is_ok(), is_ok);
+        assert_eq!(
+            generate_keys(&RandomKeySource, key_size_bits, 1).is_ok(),
+            is_ok
+        );
     }
 
     #[test_case(false, 0; "Zero")]
     #[test_case(false, -10; "Negative value")]
     #[test_case(true, 16; "Positive value")]
     fn test_num_keys_validation(is_ok: bool, num_keys: i32) {
-        assert_eq!(generate_random_keys(8, num_keys).is_ok(), is_ok);
+        assert_eq!(generate_keys(&RandomKeySource, 8, num_keys).is_ok(), is_ok);
     }
 
     #[test]
@@ -241,7 +365,7 @@ mod tests {
         let key_size_bits: i32 = 16;
         let num_keys: i32 = 2;
 
-        let result = generate_random_keys(key_size_bits, num_keys);
+        let result = generate_keys(&RandomKeySource, key_size_bits, num_keys);
         assert!(result.is_ok());
         let key_container = result.unwrap();
 
@@ -251,4 +375,35 @@ mod tests {
             assert_eq!(key.content.len(), 4);
         }
     }
+
+    #[test]
+    fn test_key_debug_redacts_content() {
+        let key = &generate_keys(&RandomKeySource, 256, 1).unwrap()[0];
+
+        assert!(!format!("{:?}", key).contains(key.content.as_str()));
+    }
+
+    fn policy_with_ttl(key_ttl_secs: u64) -> KeyPolicy {
+        KeyPolicy {
+            key_ttl_secs,
+            ..KeyPolicy::for_tests()
+        }
+    }
+
+    #[test_case(vec![], None; "No policies")]
+    #[test_case(vec![0, 0], None; "Never expiring")]
+    #[test_case(vec![60, 0, 30], Some(30); "Shortest time-to-live")]
+    #[test_case(vec![u64::MAX], None; "Unrepresentable time-to-live")]
+    fn test_expiry(key_ttl_secs: Vec<u64>, expected_secs: Option<i64>) {
+        let policies: Vec<KeyPolicy> =
+            key_ttl_secs.into_iter().map(policy_with_ttl).collect();
+
+        let before = Utc::now();
+        let expires_at = expiry(&policies);
+
+        assert_eq!(
+            expires_at.map(|expires_at| (expires_at - before).num_seconds()),
+            expected_secs
+        );
+    }
 }
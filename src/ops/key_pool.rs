@@ -0,0 +1,524 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::models::key::Key;
+use crate::models::peer::{PeerKey, PoolBlock, PoolBlocks};
+use crate::models::policy::KeyPolicy;
+use crate::ops::{self, key_source::KeySource, kme::Link};
+use crate::{converter, db};
+use actix_web::{rt, web};
+use log::{debug, error, info};
+use sqlx::{PgConnection, PgPool};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/// Number of pool blocks required to build a single key of the given size.
+pub fn blocks_per_key(key_size_bits: i32) -> i64 {
+    num_blocks(key_size_bits, CONFIG.key_pool_block_size)
+}
+
+fn num_blocks(key_size_bits: i32, block_size: i32) -> i64 {
+    let block_size = i64::from(block_size);
+    (i64::from(key_size_bits) + block_size - 1) / block_size
+}
+
+/// Returns the number of keys of the given size that can be delivered over
+/// the link using the key material currently in the pool.
+pub async fn count_available_keys(
+    pool: &PgPool,
+    link: &Link,
+    key_size_bits: i32,
+) -> Result<i32, Error> {
+    let num_blocks = count_blocks(pool, link).await?;
+
+    match (num_blocks / blocks_per_key(key_size_bits)).try_into() {
+        Ok(num_keys) => Ok(num_keys),
+        Err(e) => {
+            error!("Failed to convert key count to 'i32': {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+/// Key delivered from the pool, along with the pool blocks it was built from.
+pub struct ReservedKey {
+    pub key: Key,
+    pub blocks: Vec<PoolBlock>,
+}
+
+/// Takes the key material for `num_keys` keys out of the link's pool.
+///
+/// The blocks are only removed from the pool once the enclosing transaction
+/// is committed. A '503' error is returned if the pool is exhausted.
+pub async fn reserve_keys(
+    connection: &mut PgConnection,
+    link: &Link,
+    key_size_bits: i32,
+    num_keys: i32,
+) -> Result<Vec<ReservedKey>, Error> {
+    let blocks_per_key = blocks_per_key(key_size_bits);
+    let num_blocks = blocks_per_key * i64::from(num_keys);
+
+    let mut rows = match sqlx::query_file!(
+        "sql/reserve_pool_blocks.sql",
+        link.source_kme_id,
+        link.target_kme_id,
+        CONFIG.key_pool_block_size,
+        num_blocks,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to reserve key pool blocks. Error: {:?}", e);
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    if (rows.len() as i64) < num_blocks {
+        error!(
+            "Key pool for link {} -> {} exhausted",
+            link.source_kme_id, link.target_kme_id
+        );
+        return Err(Error::service_unavailable(
+            "Not enough key material available",
+        ));
+    }
+
+    rows.sort_by_key(|row| (row.created_at, row.id));
+
+    let mut blocks = Vec::with_capacity(rows.len());
+    for row in rows {
+        blocks.push(PoolBlock {
+            id: row.id,
+            content: decrypt_block(
+                link,
+                &row.id,
+                row.key_version,
+                &row.content,
+            )?,
+        });
+    }
+    let mut blocks = blocks.into_iter();
+
+    let mut keys: Vec<ReservedKey> =
+        Vec::with_capacity(usize::try_from(num_keys).unwrap_or(0));
+
+    for _ in 0..num_keys {
+        let key_blocks: Vec<PoolBlock> =
+            blocks.by_ref().take(blocks_per_key as usize).collect();
+
+        keys.push(ReservedKey {
+            key: build_key(Uuid::new_v4(), &key_blocks, key_size_bits)?,
+            blocks: key_blocks,
+        });
+    }
+
+    Ok(keys)
+}
+
+/// Takes the blocks of keys reserved by the source KME of the link out of
+/// the link's pool, building the very same keys. Relayed keys are decrypted
+/// using the key material of the blocks instead.
+pub async fn take_keys(
+    connection: &mut PgConnection,
+    link: &Link,
+    key_size_bits: i32,
+    peer_keys: &[PeerKey],
+) -> Result<Vec<ReservedKey>, Error> {
+    let block_ids: Vec<Uuid> = peer_keys
+        .iter()
+        .flat_map(|key| key.block_ids.iter().copied())
+        .collect();
+
+    let rows = match sqlx::query_file!(
+        "sql/take_pool_blocks.sql",
+        link.source_kme_id,
+        link.target_kme_id,
+        CONFIG.key_pool_block_size,
+        &block_ids,
+    )
+    .fetch_all(&mut *connection)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to take key pool blocks. Error: {:?}", e);
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    // Every row is only returned once, hence this also rejects blocks used
+    // for more than one key.
+    if rows.len() != block_ids.len() {
+        error!(
+            "{} of {} requested blocks found in key pool for link {} -> {}",
+            rows.len(),
+            block_ids.len(),
+            link.source_kme_id,
+            link.target_kme_id
+        );
+        return Err(Error::bad_request("Unknown key pool blocks supplied"));
+    }
+
+    let mut contents: HashMap<Uuid, Zeroizing<String>> =
+        HashMap::with_capacity(rows.len());
+    for row in rows {
+        let content =
+            decrypt_block(link, &row.id, row.key_version, &row.content)?;
+        contents.insert(row.id, content);
+    }
+
+    let mut keys: Vec<ReservedKey> = Vec::with_capacity(peer_keys.len());
+
+    for peer_key in peer_keys {
+        if peer_key.block_ids.len() as i64 != blocks_per_key(key_size_bits) {
+            return Err(Error::bad_request(
+                "Number of key pool blocks does not match the key size",
+            ));
+        }
+
+        let key_blocks: Vec<PoolBlock> = peer_key
+            .block_ids
+            .iter()
+            .filter_map(|id| {
+                Some(PoolBlock {
+                    id: *id,
+                    content: contents.remove(id)?,
+                })
+            })
+            .collect();
+
+        let mut key = build_key(peer_key.id, &key_blocks, key_size_bits)?;
+
+        if let Some(masked_key) = &peer_key.masked_key {
+            let pad = converter::from_base64(&key.content)?;
+            key.content =
+                converter::to_base64(&ops::relay::unmask(masked_key, &pad)?);
+        }
+
+        key.id_extension = peer_key.id_extension.clone();
+        key.extension = peer_key.extension.clone();
+        keys.push(ReservedKey {
+            key,
+            blocks: key_blocks,
+        });
+    }
+
+    Ok(keys)
+}
+
+/// Adds the blocks to the link's pool, encrypted with the current master key.
+pub async fn insert_blocks(
+    connection: &mut PgConnection,
+    link: &Link,
+    blocks: &[PoolBlock],
+) -> Result<(), Error> {
+    let key_version = ops::cipher::current_version();
+    let ids: Vec<Uuid> = blocks.iter().map(|block| block.id).collect();
+    let mut contents: Vec<String> = Vec::with_capacity(blocks.len());
+
+    for block in blocks {
+        contents.push(ops::cipher::encrypt(
+            key_version,
+            &block.content,
+            &ops::cipher::pool_associated_data(
+                &block.id,
+                &link.source_kme_id,
+                &link.target_kme_id,
+            ),
+        )?);
+    }
+
+    match sqlx::query_file!(
+        "sql/insert_pool_blocks.sql",
+        &ids,
+        link.source_kme_id,
+        link.target_kme_id,
+        CONFIG.key_pool_block_size,
+        &contents,
+        key_version,
+    )
+    .execute(&mut *connection)
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Failed to insert key pool blocks. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+fn decrypt_block(
+    link: &Link,
+    block_id: &Uuid,
+    key_version: i32,
+    content: &str,
+) -> Result<Zeroizing<String>, Error> {
+    ops::cipher::decrypt(
+        key_version,
+        content,
+        &ops::cipher::pool_associated_data(
+            block_id,
+            &link.source_kme_id,
+            &link.target_kme_id,
+        ),
+    )
+}
+
+/// Puts blocks taken out of the pool back, once the keys built from them
+/// turned out not to be delivered.
+pub async fn return_blocks(
+    pool: &PgPool,
+    link: &Link,
+    blocks: &[PoolBlock],
+) -> Result<(), Error> {
+    let mut transaction = db::begin(pool).await?;
+    insert_blocks(&mut transaction, link, blocks).await?;
+    db::commit(transaction).await?;
+
+    info!(
+        "Returned {} blocks to key pool for link {} -> {}",
+        blocks.len(),
+        link.source_kme_id,
+        link.target_kme_id
+    );
+
+    Ok(())
+}
+
+/// Periodically tops up the pools of the links this KME is responsible for.
+pub async fn run_producer(pool: PgPool, key_source: Arc<dyn KeySource>) {
+    let mut interval = rt::time::interval(Duration::from_secs(
+        CONFIG.key_pool_refill_interval_secs,
+    ));
+
+    info!("Key pool producer started");
+
+    loop {
+        interval.tick().await;
+
+        for link in ops::kme::outgoing_links() {
+            if let Err(e) = refill(&pool, &key_source, &link).await {
+                error!(
+                    "Failed to refill key pool for link {} -> {}: {}",
+                    link.source_kme_id, link.target_kme_id, e
+                );
+            }
+        }
+    }
+}
+
+async fn refill(
+    pool: &PgPool,
+    key_source: &Arc<dyn KeySource>,
+    link: &Link,
+) -> Result<(), Error> {
+    let slave_sae_ids: HashSet<String> = ops::registry::list(pool)
+        .await?
+        .into_iter()
+        .filter(|entry| ops::kme::is_routed_over(&entry.kme_id, link))
+        .map(|entry| entry.sae_id)
+        .collect();
+    let capacity = capacity(
+        &ops::policy::for_slaves(&slave_sae_ids),
+        CONFIG.key_pool_block_size,
+    );
+    let num_missing_blocks = (capacity - count_blocks(pool, link).await?)
+        .min(i64::from(CONFIG.key_pool_batch_size));
+
+    if num_missing_blocks <= 0 {
+        return Ok(());
+    }
+
+    // Key sources may block while waiting for key material, hence they are
+    // not polled on the async runtime.
+    let key_source = key_source.clone();
+    let blocks = match web::block(move || {
+        generate_blocks(key_source.as_ref(), num_missing_blocks)
+    })
+    .await
+    {
+        Ok(blocks) => blocks?,
+        Err(e) => {
+            error!("Key generation task failed. Error: {:?}", e);
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    let blocks: Vec<PoolBlock> = blocks
+        .into_iter()
+        .map(|block| PoolBlock {
+            id: block.id,
+            content: block.content,
+        })
+        .collect();
+    let num_added_blocks = blocks.len();
+
+    // The blocks are only kept once the target KME holds them as well. Blocks
+    // the target KME holds, but this KME does not, are never used.
+    let blocks = if link.is_remote() {
+        let pool_blocks = PoolBlocks {
+            source_kme_id: link.source_kme_id.clone(),
+            target_kme_id: link.target_kme_id.clone(),
+            size: CONFIG.key_pool_block_size,
+            blocks,
+        };
+        ops::peer::push_pool_blocks(&pool_blocks).await?;
+        pool_blocks.blocks
+    } else {
+        blocks
+    };
+
+    let mut transaction = db::begin(pool).await?;
+    insert_blocks(&mut transaction, link, &blocks).await?;
+    db::commit(transaction).await?;
+
+    debug!(
+        "Added {} blocks to key pool for link {} -> {}",
+        num_added_blocks, link.source_kme_id, link.target_kme_id
+    );
+
+    Ok(())
+}
+
+/// Number of blocks the pool of a link holds when full, which is enough for
+/// `max_key_count` keys of `key_size` under any of the link's policies.
+fn capacity(policies: &[KeyPolicy], block_size: i32) -> i64 {
+    policies
+        .iter()
+        .map(|policy| {
+            i64::from(policy.max_key_count)
+                * num_blocks(policy.key_size, block_size)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Generates up to `num_blocks` blocks, keeping the blocks generated before
+/// the key source ran dry, as their key material has already been consumed.
+fn generate_blocks(
+    key_source: &dyn KeySource,
+    num_blocks: i64,
+) -> Result<Vec<Key>, Error> {
+    let mut blocks = Vec::new();
+
+    for _ in 0..num_blocks {
+        match ops::key::generate_keys(key_source, CONFIG.key_pool_block_size, 1)
+        {
+            Ok(mut block) => blocks.append(&mut block),
+            Err(e) if blocks.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+
+    Ok(blocks)
+}
+
+async fn count_blocks(pool: &PgPool, link: &Link) -> Result<i64, Error> {
+    match sqlx::query_file!(
+        "sql/count_pool_blocks.sql",
+        link.source_kme_id,
+        link.target_kme_id,
+        CONFIG.key_pool_block_size,
+    )
+    .fetch_one(pool)
+    .await
+    {
+        Ok(res) => Ok(res.count),
+        Err(e) => {
+            error!("Failed to count key pool blocks. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+fn build_key(
+    id: Uuid,
+    blocks: &[PoolBlock],
+    key_size_bits: i32,
+) -> Result<Key, Error> {
+    let mut decoded_blocks: Vec<Zeroizing<Vec<u8>>> =
+        Vec::with_capacity(blocks.len());
+
+    for block in blocks {
+        decoded_blocks.push(converter::from_base64(&block.content)?);
+    }
+
+    Ok(Key {
+        id,
+        id_extension: None,
+        content: converter::to_base64(&combine_blocks(
+            &decoded_blocks,
+            key_size_bits,
+        )),
+        extension: None,
+        size: key_size_bits,
+    })
+}
+
+/// Concatenates the blocks and truncates the result to the key size. Any
+/// surplus key material in the last block is discarded.
+fn combine_blocks(
+    blocks: &[Zeroizing<Vec<u8>>],
+    key_size_bits: i32,
+) -> Zeroizing<Vec<u8>> {
+    let key_size_bytes = usize::try_from(key_size_bits / 8).unwrap_or(0);
+
+    // NOTE: The capacity is reserved upfront, such that the key material is
+    // never left behind by a reallocation.
+    let mut key_material = Zeroizing::new(Vec::with_capacity(
+        blocks.iter().map(|block| block.len()).sum(),
+    ));
+
+    for block in blocks {
+        key_material.extend_from_slice(block);
+    }
+
+    key_material.truncate(key_size_bytes);
+    key_material
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case(vec![vec![1, 2]], 16, vec![1, 2]; "Single exact block")]
+    #[test_case(vec![vec![1, 2]], 8, vec![1]; "Single truncated block")]
+    #[test_case(vec![vec![1, 2], vec![3, 4]], 24, vec![1, 2, 3]; "Multiple blocks")]
+    fn test_combine_blocks(
+        blocks: Vec<Vec<u8>>,
+        key_size_bits: i32,
+        expected: Vec<u8>,
+    ) {
+        let blocks: Vec<Zeroizing<Vec<u8>>> =
+            blocks.into_iter().map(Zeroizing::new).collect();
+
+        assert_eq!(*combine_blocks(&blocks, key_size_bits), expected);
+    }
+
+    fn policy(key_size: i32, max_key_count: i32) -> KeyPolicy {
+        KeyPolicy {
+            key_size,
+            max_key_count,
+            ..KeyPolicy::for_tests()
+        }
+    }
+
+    #[test_case(vec![], 0; "No policies")]
+    #[test_case(vec![policy(256, 10)], 10; "Single block keys")]
+    #[test_case(vec![policy(264, 10)], 20; "Partial block")]
+    #[test_case(vec![policy(256, 10), policy(1024, 5)], 20; "Largest policy")]
+    fn test_capacity(policies: Vec<KeyPolicy>, expected: i64) {
+        assert_eq!(capacity(&policies, 256), expected);
+    }
+}
@@ -0,0 +1,417 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::error::Error;
+use crate::models::key_rotation::KeyRotationStatus;
+use crate::{db, ops};
+use actix_web::{http::StatusCode, rt};
+use log::{debug, error, info};
+use sqlx::{PgConnection, PgPool};
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Delay before retrying a batch that only found locked rows, doubled on
+/// every retry up to the maximum.
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    static ref STATUS: Mutex<KeyRotationStatus> =
+        Mutex::new(KeyRotationStatus::default());
+}
+
+/// Reloads the master keys and starts re-encrypting, in the background, the
+/// stored keys and pool blocks that are not encrypted with the current master
+/// key.
+///
+/// Every batch is committed on its own, hence an interrupted rotation resumes
+/// where it stopped when started again. Keys locked by concurrent requests are
+/// skipped by a batch and picked up by a later one.
+pub async fn start(
+    pool: &PgPool,
+    batch_size: i64,
+) -> Result<KeyRotationStatus, Error> {
+    if batch_size <= 0 {
+        return Err(Error::bad_request("'batch_size' must be greater than 0"));
+    }
+
+    let key_version = {
+        let mut status = lock_status(&STATUS);
+
+        if status.running {
+            return Err(Error::new(
+                StatusCode::CONFLICT,
+                "Key rotation already running",
+            ));
+        }
+
+        ops::cipher::reload()?;
+
+        *status = KeyRotationStatus {
+            running: true,
+            key_version: ops::cipher::current_version(),
+            ..KeyRotationStatus::default()
+        };
+        status.key_version
+    };
+
+    rt::spawn(run(pool.clone(), key_version, batch_size));
+
+    status(pool).await
+}
+
+pub async fn status(pool: &PgPool) -> Result<KeyRotationStatus, Error> {
+    let mut status = lock_status(&STATUS).clone();
+
+    if !status.running {
+        status.key_version = ops::cipher::current_version();
+    }
+
+    status.pending_count = count_pending(pool, status.key_version).await?;
+
+    Ok(status)
+}
+
+async fn count_pending(pool: &PgPool, key_version: i32) -> Result<i64, Error> {
+    match sqlx::query_file!("sql/count_keys_to_rewrap.sql", key_version)
+        .fetch_one(pool)
+        .await
+    {
+        Ok(row) => Ok(row.count),
+        Err(e) => {
+            error!("Failed to count keys to re-encrypt. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+async fn run(pool: PgPool, key_version: i32, batch_size: i64) {
+    info!(
+        "Re-encrypting stored keys and pool blocks with master key version {}",
+        key_version
+    );
+
+    run_batches(
+        &STATUS,
+        || rewrap_batch(&pool, key_version, batch_size),
+        || count_pending(&pool, key_version),
+        RETRY_DELAY,
+    )
+    .await;
+}
+
+/// Runs batches until no row is left to re-encrypt, recording the progress
+/// in the status.
+///
+/// A batch re-encrypting nothing may only have skipped rows locked by
+/// concurrent requests, hence the remaining rows are counted, and the batch
+/// retried after a growing delay while any remain.
+async fn run_batches<B, BF, C, CF>(
+    status: &Mutex<KeyRotationStatus>,
+    mut rewrap_batch: B,
+    mut count_pending: C,
+    retry_delay: Duration,
+) where
+    B: FnMut() -> BF,
+    BF: Future<Output = Result<usize, Error>>,
+    C: FnMut() -> CF,
+    CF: Future<Output = Result<i64, Error>>,
+{
+    let mut delay = retry_delay;
+
+    let result = loop {
+        match rewrap_batch().await {
+            Ok(0) => match count_pending().await {
+                Ok(0) => break Ok(()),
+                Ok(pending_count) => {
+                    debug!(
+                        "{} rows locked, retrying in {:?}",
+                        pending_count, delay
+                    );
+                    rt::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                Err(e) => break Err(e),
+            },
+            Ok(count) => {
+                lock_status(status).rewrapped_count += count as u64;
+                delay = retry_delay;
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    let mut status = lock_status(status);
+    status.running = false;
+
+    match result {
+        Ok(()) => info!(
+            "Re-encrypted {} stored keys and pool blocks with master key \
+             version {}",
+            status.rewrapped_count, status.key_version
+        ),
+        Err(e) => {
+            error!("Key rotation stopped: {}", e);
+            status.error = Some("Failed to re-encrypt stored keys".to_string());
+        }
+    }
+}
+
+/// Re-encrypts a batch of stored keys, and one of pool blocks, with the given
+/// master key version, returning the number of rows re-encrypted.
+async fn rewrap_batch(
+    pool: &PgPool,
+    key_version: i32,
+    batch_size: i64,
+) -> Result<usize, Error> {
+    let mut transaction = db::begin(pool).await?;
+
+    let count = rewrap_keys(&mut transaction, key_version, batch_size).await?
+        + rewrap_pool_blocks(&mut transaction, key_version, batch_size).await?;
+
+    db::commit(transaction).await?;
+
+    Ok(count)
+}
+
+async fn rewrap_keys(
+    connection: &mut PgConnection,
+    key_version: i32,
+    batch_size: i64,
+) -> Result<usize, Error> {
+    let rows = match sqlx::query_file!(
+        "sql/retrieve_keys_to_rewrap.sql",
+        key_version,
+        batch_size
+    )
+    .fetch_all(&mut *connection)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Failed to retrieve keys to re-encrypt. Error: {:?}", e);
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let mut ids: Vec<Uuid> = Vec::with_capacity(rows.len());
+    let mut master_sae_ids: Vec<String> = Vec::with_capacity(rows.len());
+    let mut slave_sae_ids: Vec<String> = Vec::with_capacity(rows.len());
+    let mut contents: Vec<String> = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let associated_data = ops::cipher::associated_data(
+            &row.id,
+            &row.master_sae_id,
+            &row.slave_sae_id,
+        );
+
+        let content = ops::cipher::decrypt(
+            row.key_version,
+            &row.content,
+            &associated_data,
+        )?;
+
+        contents.push(ops::cipher::encrypt(
+            key_version,
+            &content,
+            &associated_data,
+        )?);
+        ids.push(row.id);
+        master_sae_ids.push(row.master_sae_id);
+        slave_sae_ids.push(row.slave_sae_id);
+    }
+
+    if let Err(e) = sqlx::query_file!(
+        "sql/update_key_contents.sql",
+        &ids,
+        &master_sae_ids,
+        &slave_sae_ids,
+        &contents,
+        key_version
+    )
+    .execute(&mut *connection)
+    .await
+    {
+        error!("Failed to save re-encrypted keys. Error: {:?}", e);
+        return Err(Error::internal_server_error());
+    }
+
+    Ok(ids.len())
+}
+
+async fn rewrap_pool_blocks(
+    connection: &mut PgConnection,
+    key_version: i32,
+    batch_size: i64,
+) -> Result<usize, Error> {
+    let rows = match sqlx::query_file!(
+        "sql/retrieve_pool_blocks_to_rewrap.sql",
+        key_version,
+        batch_size
+    )
+    .fetch_all(&mut *connection)
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(
+                "Failed to retrieve pool blocks to re-encrypt. Error: {:?}",
+                e
+            );
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let mut ids: Vec<Uuid> = Vec::with_capacity(rows.len());
+    let mut contents: Vec<String> = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let associated_data = ops::cipher::pool_associated_data(
+            &row.id,
+            &row.source_kme_id,
+            &row.target_kme_id,
+        );
+
+        let content = ops::cipher::decrypt(
+            row.key_version,
+            &row.content,
+            &associated_data,
+        )?;
+
+        contents.push(ops::cipher::encrypt(
+            key_version,
+            &content,
+            &associated_data,
+        )?);
+        ids.push(row.id);
+    }
+
+    if let Err(e) = sqlx::query_file!(
+        "sql/update_pool_block_contents.sql",
+        &ids,
+        &contents,
+        key_version
+    )
+    .execute(&mut *connection)
+    .await
+    {
+        error!("Failed to save re-encrypted pool blocks. Error: {:?}", e);
+        return Err(Error::internal_server_error());
+    }
+
+    Ok(ids.len())
+}
+
+fn lock_status(
+    status: &Mutex<KeyRotationStatus>,
+) -> MutexGuard<'_, KeyRotationStatus> {
+    match status.lock() {
+        Ok(status) => status,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// Runs the batches against scripted batch and count results, returning
+    /// the final status and the number of batches run.
+    async fn run_scripted(
+        batches: Vec<Result<usize, Error>>,
+        counts: Vec<Result<i64, Error>>,
+    ) -> (KeyRotationStatus, usize) {
+        let status = Mutex::new(KeyRotationStatus {
+            running: true,
+            key_version: 2,
+            ..KeyRotationStatus::default()
+        });
+        let batches = RefCell::new(VecDeque::from(batches));
+        let counts = RefCell::new(VecDeque::from(counts));
+        let num_batches = RefCell::new(0);
+
+        run_batches(
+            &status,
+            || {
+                *num_batches.borrow_mut() += 1;
+                let batch = batches.borrow_mut().pop_front().unwrap();
+                async move { batch }
+            },
+            || {
+                let count = counts.borrow_mut().pop_front().unwrap();
+                async move { count }
+            },
+            Duration::ZERO,
+        )
+        .await;
+
+        let status = lock_status(&status).clone();
+        let num_batches = *num_batches.borrow();
+        (status, num_batches)
+    }
+
+    #[actix_web::test]
+    async fn test_run_until_nothing_pending() {
+        let (status, num_batches) =
+            run_scripted(vec![Ok(3), Ok(2), Ok(0)], vec![Ok(0)]).await;
+
+        assert_eq!(num_batches, 3);
+        assert!(!status.running);
+        assert_eq!(status.rewrapped_count, 5);
+        assert_eq!(status.error, None);
+    }
+
+    #[actix_web::test]
+    async fn test_retry_while_rows_locked() {
+        let (status, num_batches) = run_scripted(
+            vec![Ok(3), Ok(0), Ok(0), Ok(2), Ok(0)],
+            vec![Ok(2), Ok(2), Ok(0)],
+        )
+        .await;
+
+        assert_eq!(num_batches, 5);
+        assert!(!status.running);
+        assert_eq!(status.rewrapped_count, 5);
+        assert_eq!(status.error, None);
+    }
+
+    #[actix_web::test]
+    async fn test_stop_on_batch_error() {
+        let (status, num_batches) = run_scripted(
+            vec![Ok(3), Err(Error::internal_server_error())],
+            vec![],
+        )
+        .await;
+
+        assert_eq!(num_batches, 2);
+        assert!(!status.running);
+        assert_eq!(status.rewrapped_count, 3);
+        assert!(status.error.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_stop_on_count_error() {
+        let (status, _) = run_scripted(
+            vec![Ok(0)],
+            vec![Err(Error::internal_server_error())],
+        )
+        .await;
+
+        assert!(!status.running);
+        assert!(status.error.is_some());
+    }
+}
@@ -0,0 +1,376 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use log::{error, info};
+use rand::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Source of the key material delivered to the SAEs.
+pub trait KeySource: Send + Sync {
+    /// Fills the whole of `buffer` with fresh key material.
+    fn fill_bytes(&self, buffer: &mut [u8]) -> Result<(), Error>;
+}
+
+/// Generates key material using the thread local random number generator.
+pub struct RandomKeySource;
+
+impl KeySource for RandomKeySource {
+    fn fill_bytes(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        thread_rng().fill_bytes(buffer);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamKind {
+    File,
+    Fifo,
+    UnixSocket,
+}
+
+/// Reads raw key material, e.g. as dumped by a QKD device, from a file, a
+/// named pipe or a Unix socket.
+///
+/// The number of bytes consumed is persisted to the offset file before any
+/// of them are handed out, such that no key material is ever used twice,
+/// including across restarts. Files are resumed at the persisted offset,
+/// while pipes and sockets never deliver the same bytes twice to begin with.
+pub struct StreamKeySource {
+    kind: StreamKind,
+    path: PathBuf,
+    offset_file: PathBuf,
+    state: Mutex<StreamState>,
+}
+
+struct StreamState {
+    reader: Option<Box<dyn Read + Send>>,
+    offset: u64,
+}
+
+impl StreamKeySource {
+    pub fn new(
+        kind: StreamKind,
+        path: &Path,
+        offset_file: &Path,
+    ) -> io::Result<Self> {
+        let offset = read_offset(offset_file)?;
+
+        // A file shorter than what was already consumed has been replaced or
+        // truncated, reading from it could hand out key material twice.
+        if kind == StreamKind::File && fs::metadata(path)?.len() < offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "'{}' is shorter than the {} bytes already consumed",
+                    path.display(),
+                    offset
+                ),
+            ));
+        }
+
+        Ok(Self {
+            kind,
+            path: path.to_path_buf(),
+            offset_file: offset_file.to_path_buf(),
+            state: Mutex::new(StreamState {
+                reader: None,
+                offset,
+            }),
+        })
+    }
+
+    pub fn offset(&self) -> u64 {
+        match self.state.lock() {
+            Ok(state) => state.offset,
+            Err(poisoned) => poisoned.into_inner().offset,
+        }
+    }
+
+    fn open(&self, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        match self.kind {
+            StreamKind::File => {
+                let mut file = File::open(&self.path)?;
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            }
+            // NOTE: Opening a named pipe blocks until a writer shows up.
+            StreamKind::Fifo => Ok(Box::new(File::open(&self.path)?)),
+            StreamKind::UnixSocket => {
+                Ok(Box::new(UnixStream::connect(&self.path)?))
+            }
+        }
+    }
+}
+
+impl KeySource for StreamKeySource {
+    fn fill_bytes(&self, buffer: &mut [u8]) -> Result<(), Error> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Key source state poisoned. Error: {:?}", e);
+                return Err(Error::internal_server_error());
+            }
+        };
+
+        let mut reader = match state.reader.take() {
+            Some(reader) => reader,
+            None => match self.open(state.offset) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    error!(
+                        "Failed to open key source '{}'. Error: {:?}",
+                        self.path.display(),
+                        e
+                    );
+                    return Err(Error::internal_server_error());
+                }
+            },
+        };
+
+        let num_read = read_fully(reader.as_mut(), buffer);
+
+        if num_read < buffer.len() {
+            error!(
+                "Key source '{}' delivered {} of {} bytes",
+                self.path.display(),
+                num_read,
+                buffer.len()
+            );
+
+            // Bytes partially read from a pipe or socket are gone and are
+            // discarded, whereas a file is simply read again from the last
+            // persisted offset once it has grown.
+            if self.kind != StreamKind::File {
+                state.offset += num_read as u64;
+                let _ = write_offset(&self.offset_file, state.offset);
+            }
+
+            return Err(Error::internal_server_error());
+        }
+
+        let offset = state.offset + num_read as u64;
+
+        if let Err(e) = write_offset(&self.offset_file, offset) {
+            error!(
+                "Failed to persist key source offset to '{}'. Error: {:?}",
+                self.offset_file.display(),
+                e
+            );
+
+            // The read bytes are never handed out. Files are reopened at the
+            // last persisted offset, so it stays in line with the reader.
+            if self.kind != StreamKind::File {
+                state.offset = offset;
+                state.reader = Some(reader);
+            }
+
+            return Err(Error::internal_server_error());
+        }
+
+        state.offset = offset;
+        state.reader = Some(reader);
+
+        Ok(())
+    }
+}
+
+/// Reads until `buffer` is full or the reader fails, returning the number of
+/// bytes read.
+fn read_fully(reader: &mut dyn Read, buffer: &mut [u8]) -> usize {
+    let mut num_read = 0;
+
+    while num_read < buffer.len() {
+        match reader.read(&mut buffer[num_read..]) {
+            Ok(0) => break,
+            Ok(n) => num_read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Failed to read from key source. Error: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    num_read
+}
+
+fn read_offset(offset_file: &Path) -> io::Result<u64> {
+    let content = match fs::read_to_string(offset_file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    content.trim().parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid offset in '{}': {}", offset_file.display(), e),
+        )
+    })
+}
+
+/// Atomically replaces the offset file, such that a crash never leaves a
+/// partially written offset behind.
+fn write_offset(offset_file: &Path, offset: u64) -> io::Result<()> {
+    let mut tmp_path = offset_file.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(offset.to_string().as_bytes())?;
+    tmp_file.sync_all()?;
+
+    fs::rename(&tmp_path, offset_file)
+}
+
+/// Checks that the key source is known, and that a path is configured for
+/// the ones reading from a stream.
+pub fn check_config(
+    key_source: &str,
+    key_source_path: Option<&str>,
+) -> Result<(), String> {
+    match (parse_kind(key_source)?, key_source_path) {
+        (Some(_), None) => {
+            Err(format!("key source '{}' requires a path", key_source))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the kind of stream the key source reads from, if any, the random
+/// number generator being used otherwise.
+fn parse_kind(key_source: &str) -> Result<Option<StreamKind>, String> {
+    match key_source {
+        "random" => Ok(None),
+        "file" => Ok(Some(StreamKind::File)),
+        "fifo" => Ok(Some(StreamKind::Fifo)),
+        "unix" => Ok(Some(StreamKind::UnixSocket)),
+        _ => Err(format!(
+            "unknown key source '{}', expected 'random', 'file', 'fifo' or \
+             'unix'",
+            key_source
+        )),
+    }
+}
+
+/// Builds the key source selected in the configuration.
+pub fn from_config() -> Arc<dyn KeySource> {
+    let kind = match parse_kind(&CONFIG.key_source) {
+        Ok(Some(kind)) => kind,
+        Ok(None) => {
+            info!("Using random number generator as key source");
+            return Arc::new(RandomKeySource);
+        }
+        Err(e) => {
+            error!("Invalid key source configured: {}", e);
+            panic!("Invalid key source configured")
+        }
+    };
+
+    let path = match &CONFIG.key_source_path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            error!("Key source '{}' requires a path", CONFIG.key_source);
+            panic!("Key source path not set")
+        }
+    };
+
+    let offset_file = match &CONFIG.key_source_offset_file {
+        Some(offset_file) => PathBuf::from(offset_file),
+        None => {
+            let mut offset_file = path.as_os_str().to_owned();
+            offset_file.push(".offset");
+            PathBuf::from(offset_file)
+        }
+    };
+
+    match StreamKeySource::new(kind, &path, &offset_file) {
+        Ok(key_source) => {
+            info!(
+                "Using {:?} '{}' as key source, {} bytes already consumed",
+                kind,
+                path.display(),
+                key_source.offset()
+            );
+            Arc::new(key_source)
+        }
+        Err(e) => {
+            error!(
+                "Failed to set up key source '{}'. Error: {:?}",
+                path.display(),
+                e
+            );
+            panic!("Invalid key source '{}'", path.display())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::env;
+    use test_case::test_case;
+    use uuid::Uuid;
+
+    fn temp_paths() -> (PathBuf, PathBuf) {
+        let path =
+            env::temp_dir().join(format!("key_source_{}", Uuid::new_v4()));
+        let mut offset_file = path.as_os_str().to_owned();
+        offset_file.push(".offset");
+        (path, PathBuf::from(offset_file))
+    }
+
+    #[test]
+    fn test_file_key_source_resumes_after_consumed_offset() {
+        let (path, offset_file) = temp_paths();
+        fs::write(&path, (0..10).collect::<Vec<u8>>()).unwrap();
+
+        let key_source =
+            StreamKeySource::new(StreamKind::File, &path, &offset_file)
+                .unwrap();
+        let mut buffer = [0u8; 4];
+        key_source.fill_bytes(&mut buffer).unwrap();
+        assert_eq!(buffer, [0, 1, 2, 3]);
+        assert_eq!(read_offset(&offset_file).unwrap(), 4);
+
+        let key_source =
+            StreamKeySource::new(StreamKind::File, &path, &offset_file)
+                .unwrap();
+        key_source.fill_bytes(&mut buffer).unwrap();
+        assert_eq!(buffer, [4, 5, 6, 7]);
+
+        // Only two bytes are left, which are not consumed.
+        assert!(key_source.fill_bytes(&mut buffer).is_err());
+        assert_eq!(key_source.offset(), 8);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&offset_file).unwrap();
+    }
+
+    #[test]
+    fn test_file_key_source_rejects_truncated_file() {
+        let (path, offset_file) = temp_paths();
+        fs::write(&path, [0u8; 4]).unwrap();
+        write_offset(&offset_file, 8).unwrap();
+
+        assert!(StreamKeySource::new(StreamKind::File, &path, &offset_file)
+            .is_err());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&offset_file).unwrap();
+    }
+
+    #[test_case("random", None, true; "Random")]
+    #[test_case("file", Some("/dev/qkd"), true; "File")]
+    #[test_case("unix", None, false; "Stream without path")]
+    #[test_case("device", Some("/dev/qkd"), false; "Unknown source")]
+    fn test_check_config(key_source: &str, path: Option<&str>, is_ok: bool) {
+        assert_eq!(check_config(key_source, path).is_ok(), is_ok);
+    }
+}
@@ -0,0 +1,147 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use log::error;
+use serde_json::json;
+use std::collections::HashMap;
+
+/// Quantum link between two KMEs, over which key material is established.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub source_kme_id: String,
+    pub target_kme_id: String,
+}
+
+impl Link {
+    /// Whether the key material is shared with another KME, rather than
+    /// kept by this KME for SAEs connected to itself.
+    pub fn is_remote(&self) -> bool {
+        self.target_kme_id != self.source_kme_id
+    }
+}
+
+/// Keys can only be relayed over links this KME has a key pool for, hence
+/// every route must go through a peer.
+pub fn check_routes(
+    kme_routes: &HashMap<String, String>,
+    peer_kmes: &HashMap<String, String>,
+) -> Result<(), String> {
+    let mut kme_ids: Vec<&String> = kme_routes.keys().collect();
+    kme_ids.sort();
+
+    for kme_id in kme_ids {
+        let next_hop = &kme_routes[kme_id];
+
+        if !peer_kmes.contains_key(next_hop) {
+            return Err(format!(
+                "route to KME '{}' goes through '{}', which is not a peer",
+                kme_id, next_hop
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the first link on the path to the given KME. Peers are reached
+/// directly, whereas any other KME is reached through the next hop in the
+/// routing table.
+pub fn link_towards(kme_id: &str) -> Result<Link, Error> {
+    match next_hop(kme_id) {
+        Some(next_hop) => Ok(Link {
+            source_kme_id: CONFIG.kme_id.clone(),
+            target_kme_id: next_hop.to_string(),
+        }),
+        None => {
+            error!("No route to KME '{}'", kme_id);
+            Err(Error::bad_request("No route to KME")
+                .with_detail(json!({ "KME_ID": kme_id })))
+        }
+    }
+}
+
+fn next_hop(kme_id: &str) -> Option<&str> {
+    find_next_hop(
+        kme_id,
+        &CONFIG.kme_id,
+        &CONFIG.peer_kmes,
+        &CONFIG.kme_routes,
+    )
+}
+
+fn find_next_hop<'a>(
+    kme_id: &'a str,
+    own_kme_id: &str,
+    peer_kmes: &HashMap<String, String>,
+    kme_routes: &'a HashMap<String, String>,
+) -> Option<&'a str> {
+    if kme_id == own_kme_id || peer_kmes.contains_key(kme_id) {
+        return Some(kme_id);
+    }
+
+    kme_routes.get(kme_id).map(String::as_str)
+}
+
+/// Whether keys for SAEs connected to the given KME are established over the
+/// link.
+pub fn is_routed_over(kme_id: &str, link: &Link) -> bool {
+    next_hop(kme_id) == Some(link.target_kme_id.as_str())
+}
+
+/// Returns the links whose key pools are filled by this KME, i.e. the link to
+/// itself and the links to each of its peers.
+pub fn outgoing_links() -> Vec<Link> {
+    let mut target_kme_ids: Vec<&String> = CONFIG.peer_kmes.keys().collect();
+    target_kme_ids.sort();
+
+    std::iter::once(&CONFIG.kme_id)
+        .chain(target_kme_ids)
+        .map(|target_kme_id| Link {
+            source_kme_id: CONFIG.kme_id.clone(),
+            target_kme_id: target_kme_id.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    fn to_map(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test_case(true, &[]; "No routes")]
+    #[test_case(true, &[("kme_003", "kme_002")]; "Route through peer")]
+    #[test_case(false, &[("kme_003", "kme_004")]; "Route through non-peer")]
+    #[test_case(false, &[("kme_003", "kme_002"), ("kme_005", "kme_003")]; "Route through routed KME")]
+    fn test_check_routes(is_ok: bool, kme_routes: &[(&str, &str)]) {
+        let peer_kmes = to_map(&[("kme_002", "https://kme_002")]);
+
+        assert_eq!(
+            check_routes(&to_map(kme_routes), &peer_kmes).is_ok(),
+            is_ok
+        );
+    }
+
+    #[test_case("kme_001", Some("kme_001"); "This KME")]
+    #[test_case("kme_002", Some("kme_002"); "Peer")]
+    #[test_case("kme_003", Some("kme_002"); "Routed through peer")]
+    #[test_case("kme_004", None; "No route")]
+    fn test_find_next_hop(kme_id: &str, expected_next_hop: Option<&str>) {
+        let peer_kmes = to_map(&[("kme_002", "https://kme_002")]);
+        let kme_routes = to_map(&[("kme_003", "kme_002")]);
+
+        assert_eq!(
+            find_next_hop(kme_id, "kme_001", &peer_kmes, &kme_routes),
+            expected_next_hop
+        );
+    }
+}
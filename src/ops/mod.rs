@@ -1,5 +1,19 @@
 // SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
 // SPDX-License-Identifier: AGPL-3.0-only
 
+pub mod acl;
+pub mod admin;
+pub mod cipher;
+pub mod crl;
+pub mod extension;
+pub mod identity;
 pub mod key;
+pub mod key_pool;
+pub mod key_rotation;
+pub mod key_source;
+pub mod kme;
+pub mod peer;
+pub mod policy;
+pub mod registry;
+pub mod relay;
 pub mod server;
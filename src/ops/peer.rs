@@ -0,0 +1,251 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::common;
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::models::connection_info::ConnectionInfo;
+use crate::models::peer::{PeerKeys, PoolBlocks};
+use crate::ops::{self, kme::Link};
+use awc::error::SendRequestError;
+use awc::{Client, Connector};
+use log::{debug, error};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    /// Connectors to the peer KMEs, built on startup and on every reload.
+    static ref PEER_CONNECTORS: RwLock<Arc<HashMap<String, SslConnector>>> =
+        RwLock::new(Arc::new(HashMap::new()));
+}
+
+/// Loads the certificates presented to, and used to verify, the peer KMEs.
+/// On reload, the current ones are kept if the new ones are invalid.
+pub fn reload() -> Result<(), ErrorStack> {
+    common::store(&PEER_CONNECTORS, Arc::new(build_connectors()?));
+
+    Ok(())
+}
+
+/// Failure to hand something over to a peer KME.
+#[derive(Debug)]
+pub enum PushError {
+    /// The peer KME was not reached, or answered with an error, hence it kept
+    /// nothing of the request.
+    NotDelivered(Error),
+    /// The peer KME may have handled the request, its answer being lost.
+    Unknown(Error),
+}
+
+impl From<PushError> for Error {
+    fn from(e: PushError) -> Self {
+        match e {
+            PushError::NotDelivered(e) | PushError::Unknown(e) => e,
+        }
+    }
+}
+
+/// Shares newly established pool blocks with the target KME of the link.
+pub async fn push_pool_blocks(blocks: &PoolBlocks) -> Result<(), PushError> {
+    post(&blocks.target_kme_id, "/api/v1/peer/key_pool", blocks).await
+}
+
+/// Hands keys over to the target KME of the link.
+pub async fn push_keys(peer_keys: &PeerKeys) -> Result<(), PushError> {
+    post(&peer_keys.target_kme_id, "/api/v1/peer/keys", peer_keys).await
+}
+
+/// Checks that the request was issued by the source KME of the link, and that
+/// this KME is its target.
+pub fn authorize(
+    request: &actix_web::HttpRequest,
+    source_kme_id: &str,
+    target_kme_id: &str,
+) -> Result<Link, Error> {
+    authorize_link(
+        &ConnectionInfo::new(request)?.sae_id,
+        source_kme_id,
+        target_kme_id,
+        &CONFIG.kme_id,
+        &CONFIG.peer_kmes,
+    )
+}
+
+fn authorize_link(
+    peer_kme_id: &str,
+    source_kme_id: &str,
+    target_kme_id: &str,
+    kme_id: &str,
+    peer_kmes: &HashMap<String, String>,
+) -> Result<Link, Error> {
+    if peer_kme_id != source_kme_id
+        || !peer_kmes.contains_key(peer_kme_id)
+        || target_kme_id != kme_id
+    {
+        error!(
+            "'{}' is not allowed to share keys over link {} -> {}",
+            peer_kme_id, source_kme_id, target_kme_id
+        );
+        return Err(Error::unauthorized());
+    }
+
+    Ok(Link {
+        source_kme_id: source_kme_id.to_string(),
+        target_kme_id: target_kme_id.to_string(),
+    })
+}
+
+async fn post<T: Serialize>(
+    peer_kme_id: &str,
+    path: &str,
+    body: &T,
+) -> Result<(), PushError> {
+    let connectors = common::load(&PEER_CONNECTORS);
+
+    let (url, connector) = match (
+        CONFIG.peer_kmes.get(peer_kme_id),
+        connectors.get(peer_kme_id),
+    ) {
+        (Some(url), Some(connector)) => (url, connector),
+        _ => {
+            error!("Unknown peer KME '{}'", peer_kme_id);
+            return Err(
+                PushError::NotDelivered(Error::internal_server_error()),
+            );
+        }
+    };
+
+    let client = Client::builder()
+        .connector(Connector::new().openssl(connector.clone()))
+        .finish();
+    let url = format!("{}{}", url.trim_end_matches('/'), path);
+
+    match client.post(&url).send_json(body).await {
+        Ok(response) if response.status().is_success() => {
+            debug!("Peer KME '{}' accepted '{}'", peer_kme_id, url);
+            Ok(())
+        }
+        Ok(mut response) => {
+            error!(
+                "Peer KME '{}' rejected '{}' with status {}: {:?}",
+                peer_kme_id,
+                url,
+                response.status(),
+                response.body().await
+            );
+            Err(PushError::NotDelivered(Error::service_unavailable(
+                "Peer KME rejected the request",
+            )))
+        }
+        Err(SendRequestError::Connect(e)) => {
+            error!(
+                "Failed to reach peer KME '{}'. Error: {:?}",
+                peer_kme_id, e
+            );
+            Err(PushError::NotDelivered(Error::service_unavailable(
+                "Peer KME unavailable",
+            )))
+        }
+        Err(e) => {
+            error!(
+                "No answer received from peer KME '{}'. Error: {:?}",
+                peer_kme_id, e
+            );
+            Err(PushError::Unknown(Error::service_unavailable(
+                "Peer KME unavailable",
+            )))
+        }
+    }
+}
+
+fn build_connectors() -> Result<HashMap<String, SslConnector>, ErrorStack> {
+    CONFIG
+        .peer_kmes
+        .keys()
+        .map(|peer_kme_id| match build_connector(peer_kme_id) {
+            Ok(connector) => Ok((peer_kme_id.clone(), connector)),
+            Err(e) => {
+                error!(
+                    "Failed to build the tls configuration for peer KME '{}'. \
+                     Error: {:?}",
+                    peer_kme_id, e
+                );
+                Err(e)
+            }
+        })
+        .collect()
+}
+
+fn build_connector(peer_kme_id: &str) -> Result<SslConnector, ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+
+    builder.set_ca_file(&CONFIG.root_crt)?;
+    ops::crl::load(&mut builder)?;
+    builder.set_private_key_file(&CONFIG.private_key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(&CONFIG.public_crt)?;
+
+    // The SAE certificates are signed by the same root CA, hence the peer is
+    // identified the same way as SAEs are.
+    let peer_kme_id = peer_kme_id.to_string();
+    builder.set_verify_callback(
+        SslVerifyMode::PEER,
+        move |preverify_ok, context| {
+            if !preverify_ok || context.error_depth() != 0 {
+                return preverify_ok;
+            }
+
+            match context.current_cert() {
+                Some(cert) => {
+                    ops::identity::sae_id(cert).as_deref()
+                        == Some(peer_kme_id.as_str())
+                }
+                None => false,
+            }
+        },
+    );
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case(true, "kme_002", "kme_002", "kme_001"; "Peer sharing with this KME")]
+    #[test_case(false, "kme_003", "kme_002", "kme_001"; "Peer posing as another peer")]
+    #[test_case(false, "kme_004", "kme_004", "kme_001"; "Unknown KME")]
+    #[test_case(false, "kme_002", "kme_002", "kme_003"; "Link to another KME")]
+    #[test_case(false, "kme_001", "kme_001", "kme_001"; "Link to itself")]
+    fn test_authorize_link(
+        is_ok: bool,
+        peer_kme_id: &str,
+        source_kme_id: &str,
+        target_kme_id: &str,
+    ) {
+        let peer_kmes = HashMap::from([
+            ("kme_002".to_string(), "https://kme_002".to_string()),
+            ("kme_003".to_string(), "https://kme_003".to_string()),
+        ]);
+
+        let link = authorize_link(
+            peer_kme_id,
+            source_kme_id,
+            target_kme_id,
+            "kme_001",
+            &peer_kmes,
+        );
+
+        assert_eq!(
+            link.ok(),
+            is_ok.then(|| Link {
+                source_kme_id: source_kme_id.to_string(),
+                target_kme_id: target_kme_id.to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,331 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::{Config, CONFIG};
+use crate::error::Error;
+use crate::models::policy::{KeyPolicy, KeyPolicyOverride, PolicyFile};
+use log::{error, info};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+type SaePair = (String, String);
+
+lazy_static! {
+    static ref PAIR_POLICIES: HashMap<SaePair, KeyPolicy> =
+        match load_pair_policies(
+            &default_policy(),
+            CONFIG.policy_file.as_deref(),
+        ) {
+            Ok(pair_policies) => pair_policies,
+            Err(e) => {
+                error!("Invalid policy file configured: {}", e);
+                panic!("Invalid policy file configured");
+            }
+        };
+}
+
+pub fn default_policy() -> KeyPolicy {
+    base_policy(&CONFIG)
+}
+
+/// Returns the globally configured limits.
+pub fn base_policy(config: &Config) -> KeyPolicy {
+    KeyPolicy {
+        key_size: config.key_size,
+        max_key_count: config.max_key_count,
+        max_key_per_request: config.max_key_per_request,
+        max_key_size: config.max_key_size,
+        min_key_size: config.min_key_size,
+        max_sae_id_count: config.max_sae_id_count,
+        key_ttl_secs: config.key_ttl_secs,
+    }
+}
+
+/// Returns the policy applicable to the given master/slave SAE pair, falling
+/// back to the globally configured limits when no override exists.
+pub fn for_pair(master_sae_id: &str, slave_sae_id: &str) -> KeyPolicy {
+    match PAIR_POLICIES
+        .get(&(master_sae_id.to_string(), slave_sae_id.to_string()))
+    {
+        Some(policy) => policy.clone(),
+        None => default_policy(),
+    }
+}
+
+/// Returns the policies of the pairs whose slave SAE is one of the given SAEs,
+/// followed by the default policy, which applies to any other pair.
+pub fn for_slaves(slave_sae_ids: &HashSet<String>) -> Vec<KeyPolicy> {
+    PAIR_POLICIES
+        .iter()
+        .filter(|((_, slave_sae_id), _)| slave_sae_ids.contains(slave_sae_id))
+        .map(|(_, policy)| policy.clone())
+        .chain(std::iter::once(default_policy()))
+        .collect()
+}
+
+pub fn validate_key_size(
+    policy: &KeyPolicy,
+    key_size_bits: i32,
+) -> Result<(), Error> {
+    if key_size_bits < policy.min_key_size {
+        return Err(Error::bad_request(
+            format!(
+                "'size' must be greater than or equal to {}",
+                policy.min_key_size
+            )
+            .as_str(),
+        ));
+    }
+
+    if key_size_bits > policy.max_key_size {
+        return Err(Error::bad_request(
+            format!(
+                "'size' must be less than or equal to {}",
+                policy.max_key_size
+            )
+            .as_str(),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn validate_num_keys(
+    policy: &KeyPolicy,
+    num_keys: usize,
+) -> Result<(), Error> {
+    if num_keys > to_usize(policy.max_key_per_request) {
+        return Err(Error::bad_request(
+            format!(
+                "Number of requested keys exceeds the maximum of {}",
+                policy.max_key_per_request
+            )
+            .as_str(),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn validate_num_additional_sae_ids(
+    policy: &KeyPolicy,
+    num_additional_sae_ids: usize,
+) -> Result<(), Error> {
+    if num_additional_sae_ids > to_usize(policy.max_sae_id_count) {
+        return Err(Error::bad_request(
+            format!(
+                "Number of 'additional_slave_SAE_IDs' exceeds the maximum of {}",
+                policy.max_sae_id_count
+            )
+            .as_str(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn to_usize(limit: i32) -> usize {
+    // Negative limits are treated as zero, disallowing the operation.
+    usize::try_from(limit).unwrap_or(0)
+}
+
+fn apply_override(base: &KeyPolicy, limits: &KeyPolicyOverride) -> KeyPolicy {
+    KeyPolicy {
+        key_size: limits.key_size.unwrap_or(base.key_size),
+        max_key_count: limits.max_key_count.unwrap_or(base.max_key_count),
+        max_key_per_request: limits
+            .max_key_per_request
+            .unwrap_or(base.max_key_per_request),
+        max_key_size: limits.max_key_size.unwrap_or(base.max_key_size),
+        min_key_size: limits.min_key_size.unwrap_or(base.min_key_size),
+        max_sae_id_count: limits
+            .max_sae_id_count
+            .unwrap_or(base.max_sae_id_count),
+        key_ttl_secs: limits.key_ttl_secs.unwrap_or(base.key_ttl_secs),
+    }
+}
+
+pub fn check_policy(policy: &KeyPolicy) -> Result<(), String> {
+    if policy.min_key_size <= 0 || policy.min_key_size % 8 != 0 {
+        return Err(
+            "'min_key_size' must be positive and divisible by 8".to_string()
+        );
+    }
+
+    if policy.max_key_size < policy.min_key_size {
+        return Err(
+            "'max_key_size' must be greater than or equal to 'min_key_size'"
+                .to_string(),
+        );
+    }
+
+    if policy.key_size < policy.min_key_size
+        || policy.key_size > policy.max_key_size
+        || policy.key_size % 8 != 0
+    {
+        return Err("'key_size' must be divisible by 8 and lie between \
+                    'min_key_size' and 'max_key_size'"
+            .to_string());
+    }
+
+    if policy.max_key_per_request <= 0 || policy.max_key_count <= 0 {
+        return Err("'max_key_per_request' and 'max_key_count' must be \
+                    greater than zero"
+            .to_string());
+    }
+
+    if policy.max_sae_id_count < 0 {
+        return Err("'max_SAE_ID_count' cannot be negative".to_string());
+    }
+
+    Ok(())
+}
+
+/// Loads the pair policies of the policy file, if any, which override the
+/// limits of the base policy.
+pub fn load_pair_policies(
+    base: &KeyPolicy,
+    policy_file: Option<&str>,
+) -> Result<HashMap<SaePair, KeyPolicy>, String> {
+    let policy_file = match policy_file {
+        Some(policy_file) => policy_file,
+        None => return Ok(HashMap::new()),
+    };
+
+    let contents = fs::read_to_string(policy_file)
+        .map_err(|e| format!("cannot read '{}': {}", policy_file, e))?;
+
+    let pair_policies = parse_pair_policies(base, &contents)
+        .map_err(|e| format!("'{}': {}", policy_file, e))?;
+
+    info!(
+        "Loaded {} SAE pair policies from '{}'",
+        pair_policies.len(),
+        policy_file
+    );
+
+    Ok(pair_policies)
+}
+
+fn parse_pair_policies(
+    base: &KeyPolicy,
+    contents: &str,
+) -> Result<HashMap<SaePair, KeyPolicy>, String> {
+    let parsed: PolicyFile = serde_json::from_str(contents)
+        .map_err(|e| format!("invalid policy file: {}", e))?;
+
+    let mut pair_policies = HashMap::new();
+
+    for pair in parsed.pairs {
+        let policy = apply_override(base, &pair.limits);
+
+        check_policy(&policy).map_err(|e| {
+            format!(
+                "invalid policy for master '{}' and slave '{}': {}",
+                pair.master_sae_id, pair.slave_sae_id, e
+            )
+        })?;
+
+        pair_policies.insert((pair.master_sae_id, pair.slave_sae_id), policy);
+    }
+
+    Ok(pair_policies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    #[test_case(false, 56; "Below minimum")]
+    #[test_case(true, 64; "Minimum")]
+    #[test_case(true, 1024; "Maximum")]
+    #[test_case(false, 1032; "Above maximum")]
+    fn test_key_size_limits(is_ok: bool, key_size_bits: i32) {
+        assert_eq!(
+            validate_key_size(&KeyPolicy::for_tests(), key_size_bits).is_ok(),
+            is_ok
+        );
+    }
+
+    #[test_case(true, 4; "Maximum")]
+    #[test_case(false, 5; "Above maximum")]
+    fn test_num_keys_limit(is_ok: bool, num_keys: usize) {
+        assert_eq!(
+            validate_num_keys(&KeyPolicy::for_tests(), num_keys).is_ok(),
+            is_ok
+        );
+    }
+
+    #[test_case(true, 0; "No additional SAEs")]
+    #[test_case(true, 2; "Maximum")]
+    #[test_case(false, 3; "Above maximum")]
+    fn test_num_additional_sae_ids_limit(is_ok: bool, num_sae_ids: usize) {
+        assert_eq!(
+            validate_num_additional_sae_ids(
+                &KeyPolicy::for_tests(),
+                num_sae_ids
+            )
+            .is_ok(),
+            is_ok
+        );
+    }
+
+    #[test]
+    fn test_override_replaces_only_supplied_limits() {
+        let limits = KeyPolicyOverride {
+            max_key_per_request: Some(1),
+            max_key_size: Some(512),
+            key_ttl_secs: Some(0),
+            ..Default::default()
+        };
+
+        let expected = KeyPolicy {
+            max_key_per_request: 1,
+            max_key_size: 512,
+            key_ttl_secs: 0,
+            ..KeyPolicy::for_tests()
+        };
+
+        assert_eq!(apply_override(&KeyPolicy::for_tests(), &limits), expected);
+    }
+
+    #[test_case(true, KeyPolicy::for_tests(); "Valid policy")]
+    #[test_case(false, KeyPolicy { min_key_size: 60, ..KeyPolicy::for_tests() }; "Minimum not divisible by 8")]
+    #[test_case(false, KeyPolicy { max_key_size: 32, ..KeyPolicy::for_tests() }; "Maximum below minimum")]
+    #[test_case(false, KeyPolicy { key_size: 2048, ..KeyPolicy::for_tests() }; "Default key size out of range")]
+    #[test_case(false, KeyPolicy { max_key_count: 0, ..KeyPolicy::for_tests() }; "Zero capacity")]
+    fn test_policy_check(is_ok: bool, policy: KeyPolicy) {
+        assert_eq!(check_policy(&policy).is_ok(), is_ok);
+    }
+
+    #[test]
+    fn test_parse_pair_policies() {
+        let pair_policies = parse_pair_policies(
+            &KeyPolicy::for_tests(),
+            r#"{"pairs": [
+                {"master_SAE_ID": "sae_001", "slave_SAE_ID": "sae_002",
+                 "max_key_per_request": 1}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            pair_policies,
+            HashMap::from([(
+                ("sae_001".to_string(), "sae_002".to_string()),
+                KeyPolicy {
+                    max_key_per_request: 1,
+                    ..KeyPolicy::for_tests()
+                }
+            )])
+        );
+    }
+
+    #[test_case(r#"{"pairs": {}}"#; "Malformed file")]
+    #[test_case(r#"{"pairs": [{"master_SAE_ID": "sae_001", "slave_SAE_ID": "sae_002", "min_key_size": 4}]}"#; "Invalid pair policy")]
+    fn test_parse_invalid_pair_policies(contents: &str) {
+        assert!(parse_pair_policies(&KeyPolicy::for_tests(), contents).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::models::registry::SaeKme;
+use crate::ops;
+use log::{error, info};
+use serde_json::json;
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+
+/// Registers the SAEs listed in the configuration, keeping any entries that
+/// were already registered through the admin routes.
+pub async fn init(pool: &PgPool) -> Result<(), Error> {
+    let (sae_ids, kme_ids): (Vec<String>, Vec<String>) = CONFIG
+        .sae_kmes
+        .iter()
+        .map(|(sae_id, kme_id)| (sae_id.clone(), kme_id.clone()))
+        .unzip();
+
+    match sqlx::query_file!("sql/insert_sae_kmes.sql", &sae_ids, &kme_ids)
+        .execute(pool)
+        .await
+    {
+        Ok(res) => {
+            info!("Registered {} SAEs from configuration", res.rows_affected());
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to register configured SAEs. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+/// Returns the ID of the KME the given slave SAEs are connected to.
+///
+/// Unknown SAEs, as well as SAEs connected to different KMEs, are rejected
+/// with a '400' error.
+pub async fn target_kme_id(
+    pool: &PgPool,
+    slave_sae_ids: &[String],
+) -> Result<String, Error> {
+    let entries = match sqlx::query_file_as!(
+        SaeKme,
+        "sql/retrieve_sae_kmes.sql",
+        slave_sae_ids
+    )
+    .fetch_all(pool)
+    .await
+    {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to retrieve SAE registry entries. Error: {:?}", e);
+            return Err(Error::internal_server_error());
+        }
+    };
+
+    find_target_kme_id(&entries, slave_sae_ids)
+}
+
+fn find_target_kme_id(
+    entries: &[SaeKme],
+    slave_sae_ids: &[String],
+) -> Result<String, Error> {
+    let kme_ids: HashMap<&str, &str> = entries
+        .iter()
+        .map(|entry| (entry.sae_id.as_str(), entry.kme_id.as_str()))
+        .filter(|(sae_id, _)| slave_sae_ids.iter().any(|id| id == sae_id))
+        .collect();
+
+    let unknown_sae_ids: Vec<&String> = slave_sae_ids
+        .iter()
+        .filter(|sae_id| !kme_ids.contains_key(sae_id.as_str()))
+        .collect();
+
+    if !unknown_sae_ids.is_empty() {
+        error!("Unknown SAEs requested: {:?}", unknown_sae_ids);
+        return Err(unknown_sae_ids.into_iter().fold(
+            Error::bad_request("Unknown SAE ID(s) supplied"),
+            |error, sae_id| error.with_detail(json!({ "SAE_ID": sae_id })),
+        ));
+    }
+
+    // Keys are established over a single path, hence all slave SAEs must be
+    // connected to the same KME.
+    let target_kme_ids: HashSet<&str> = kme_ids.into_values().collect();
+
+    match target_kme_ids.into_iter().collect::<Vec<_>>().as_slice() {
+        [target_kme_id] => Ok(target_kme_id.to_string()),
+        _ => Err(Error::bad_request(
+            "All slave SAEs must be connected to the same KME",
+        )),
+    }
+}
+
+pub async fn list(pool: &PgPool) -> Result<Vec<SaeKme>, Error> {
+    match sqlx::query_file_as!(SaeKme, "sql/list_sae_kmes.sql")
+        .fetch_all(pool)
+        .await
+    {
+        Ok(entries) => Ok(entries),
+        Err(e) => {
+            error!("Failed to list SAE registry entries. Error: {:?}", e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+/// Registers the SAE as connected to the given KME, which must be reachable
+/// from this KME.
+pub async fn register(
+    pool: &PgPool,
+    sae_id: &str,
+    kme_id: &str,
+) -> Result<(), Error> {
+    if sae_id.trim().is_empty() || kme_id.trim().is_empty() {
+        return Err(Error::bad_request("Invalid SAE or KME ID supplied"));
+    }
+
+    ops::kme::link_towards(kme_id)?;
+
+    match sqlx::query_file!("sql/upsert_sae_kme.sql", sae_id, kme_id)
+        .execute(pool)
+        .await
+    {
+        Ok(_) => {
+            info!("Registered SAE '{}' as connected to '{}'", sae_id, kme_id);
+            Ok(())
+        }
+        Err(e) => {
+            error!("Failed to register SAE '{}'. Error: {:?}", sae_id, e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+/// Removes the SAE from the registry, returning whether it was registered.
+pub async fn unregister(pool: &PgPool, sae_id: &str) -> Result<bool, Error> {
+    match sqlx::query_file!("sql/delete_sae_kme.sql", sae_id)
+        .execute(pool)
+        .await
+    {
+        Ok(res) => {
+            info!("Unregistered SAE '{}'", sae_id);
+            Ok(res.rows_affected() > 0)
+        }
+        Err(e) => {
+            error!("Failed to unregister SAE '{}'. Error: {:?}", sae_id, e);
+            Err(Error::internal_server_error())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use test_case::test_case;
+
+    fn entries() -> Vec<SaeKme> {
+        [
+            ("sae_001", "kme_001"),
+            ("sae_002", "kme_002"),
+            ("sae_003", "kme_002"),
+            ("sae_004", "kme_003"),
+        ]
+        .into_iter()
+        .map(|(sae_id, kme_id)| SaeKme {
+            sae_id: sae_id.to_string(),
+            kme_id: kme_id.to_string(),
+        })
+        .collect()
+    }
+
+    #[test_case(&["sae_001"], Some("kme_001"); "SAE on this KME")]
+    #[test_case(&["sae_002", "sae_003"], Some("kme_002"); "SAEs on the same KME")]
+    #[test_case(&["sae_002", "sae_004"], None; "SAEs on different KMEs")]
+    #[test_case(&["sae_005"], None; "Unknown SAE")]
+    #[test_case(&["sae_002", "sae_005"], None; "Known and unknown SAEs")]
+    fn test_find_target_kme_id(
+        slave_sae_ids: &[&str],
+        expected_kme_id: Option<&str>,
+    ) {
+        let slave_sae_ids: Vec<String> =
+            slave_sae_ids.iter().map(|sae_id| sae_id.to_string()).collect();
+
+        assert_eq!(
+            find_target_kme_id(&entries(), &slave_sae_ids).ok().as_deref(),
+            expected_kme_id
+        );
+    }
+}
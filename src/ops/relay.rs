@@ -0,0 +1,260 @@
+// SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use crate::config::CONFIG;
+use crate::converter;
+use crate::error::Error;
+use crate::models::key::KeyContainer;
+use crate::models::peer::{PeerKey, PeerKeys, PoolBlock};
+use crate::ops::{self, kme::Link, peer::PushError};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use sqlx::{PgConnection, PgPool};
+use zeroize::Zeroizing;
+
+/// Keys to be handed over to the target KME of a link, along with the pool
+/// blocks they were built from, or encrypted with.
+pub struct Handover {
+    peer_keys: PeerKeys,
+    blocks: Vec<PoolBlock>,
+}
+
+/// Prepares keys built from the link's pool blocks to be handed over to the
+/// next KME on the path to the slave SAEs, which only needs the IDs of the
+/// blocks.
+pub fn forward_keys(
+    link: &Link,
+    master_sae_id: &str,
+    slave_sae_ids: &[String],
+    key_container: &KeyContainer,
+    key_blocks: Vec<Vec<PoolBlock>>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Handover {
+    let mut blocks = Vec::new();
+    let mut peer_keys = Vec::with_capacity(key_container.keys.len());
+
+    for (key, key_blocks) in key_container.keys.iter().zip(key_blocks) {
+        peer_keys.push(PeerKey {
+            id: key.id,
+            id_extension: key.id_extension.clone(),
+            extension: key.extension.clone(),
+            block_ids: key_blocks.iter().map(|block| block.id).collect(),
+            masked_key: None,
+        });
+        blocks.extend(key_blocks);
+    }
+
+    Handover {
+        peer_keys: build_peer_keys(
+            link,
+            master_sae_id,
+            slave_sae_ids,
+            key_container,
+            peer_keys,
+            0,
+            expires_at,
+        ),
+        blocks,
+    }
+}
+
+/// Prepares keys received from a previous hop to be relayed to the next KME
+/// on the path to the slave SAEs.
+///
+/// Every key is one-time pad encrypted with key material taken out of the
+/// pool of the next link, such that it never crosses the classical channel
+/// in the clear. The key material is only removed from the pool once the
+/// enclosing transaction is committed.
+pub async fn relay_keys(
+    connection: &mut PgConnection,
+    link: &Link,
+    master_sae_id: &str,
+    slave_sae_ids: &[String],
+    key_container: &KeyContainer,
+    hop_count: u32,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<Handover, Error> {
+    if hop_count >= CONFIG.max_relay_hops {
+        error!(
+            "Keys for {:?} relayed by {} KMEs already, dropping them",
+            slave_sae_ids, hop_count
+        );
+        return Err(Error::bad_request("Maximum number of relay hops reached"));
+    }
+
+    let key_size = key_container.keys.first().map_or(0, |key| key.size);
+    let pads = ops::key_pool::reserve_keys(
+        connection,
+        link,
+        key_size,
+        key_container.keys.len() as i32,
+    )
+    .await?;
+
+    let mut blocks = Vec::new();
+    let mut peer_keys: Vec<PeerKey> =
+        Vec::with_capacity(key_container.keys.len());
+
+    for (key, pad) in key_container.keys.iter().zip(pads) {
+        let masked_key = xor(
+            &converter::from_base64(&key.content)?,
+            &converter::from_base64(&pad.key.content)?,
+        );
+
+        peer_keys.push(PeerKey {
+            id: key.id,
+            id_extension: key.id_extension.clone(),
+            extension: key.extension.clone(),
+            block_ids: pad.blocks.iter().map(|block| block.id).collect(),
+            masked_key: Some(converter::to_base64(&masked_key).to_string()),
+        });
+        blocks.extend(pad.blocks);
+    }
+
+    Ok(Handover {
+        peer_keys: build_peer_keys(
+            link,
+            master_sae_id,
+            slave_sae_ids,
+            key_container,
+            peer_keys,
+            hop_count + 1,
+            expires_at,
+        ),
+        blocks,
+    })
+}
+
+/// Hands the keys over to the next KME, once the pool blocks they use have
+/// been taken out of the pool, such that no block is locked while waiting for
+/// the next KME.
+///
+/// The blocks are put back into the pool if the next KME kept nothing. They
+/// are discarded if it may have kept the keys, as they must never be used
+/// twice.
+pub async fn hand_over(pool: &PgPool, handover: Handover) -> Result<(), Error> {
+    let Handover { peer_keys, blocks } = handover;
+    let link = Link {
+        source_kme_id: peer_keys.source_kme_id.clone(),
+        target_kme_id: peer_keys.target_kme_id.clone(),
+    };
+
+    info!(
+        "Handing {} keys for {:?} over to KME '{}'",
+        peer_keys.keys.len(),
+        peer_keys.slave_sae_ids,
+        link.target_kme_id
+    );
+
+    match ops::peer::push_keys(&peer_keys).await {
+        Ok(()) => Ok(()),
+        Err(PushError::NotDelivered(e)) => {
+            if let Err(return_error) =
+                ops::key_pool::return_blocks(pool, &link, &blocks).await
+            {
+                error!(
+                    "Failed to return {} blocks to key pool for link {} -> {}: \
+                     {}",
+                    blocks.len(),
+                    link.source_kme_id,
+                    link.target_kme_id,
+                    return_error
+                );
+            }
+            Err(e)
+        }
+        Err(PushError::Unknown(e)) => {
+            error!(
+                "Discarding {} blocks of key pool for link {} -> {}, as KME \
+                 '{}' may have used them",
+                blocks.len(),
+                link.source_kme_id,
+                link.target_kme_id,
+                link.target_kme_id
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Recovers a key relayed by the previous hop, using the key material of the
+/// pool blocks it was encrypted with.
+pub fn unmask(
+    masked_key: &str,
+    pad: &[u8],
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let masked_key = match converter::decode_base64(masked_key) {
+        Ok(masked_key) => masked_key,
+        Err(e) => {
+            warn!("Failed to decode relayed key. Error: {:?}", e);
+            return Err(Error::bad_request("Invalid relayed key encoding"));
+        }
+    };
+
+    if masked_key.len() != pad.len() {
+        error!(
+            "Relayed key of {} bytes does not match pad of {} bytes",
+            masked_key.len(),
+            pad.len()
+        );
+        return Err(Error::bad_request("Relayed key size mismatch"));
+    }
+
+    Ok(xor(&masked_key, pad))
+}
+
+fn build_peer_keys(
+    link: &Link,
+    master_sae_id: &str,
+    slave_sae_ids: &[String],
+    key_container: &KeyContainer,
+    keys: Vec<PeerKey>,
+    hop_count: u32,
+    expires_at: Option<DateTime<Utc>>,
+) -> PeerKeys {
+    PeerKeys {
+        source_kme_id: link.source_kme_id.clone(),
+        target_kme_id: link.target_kme_id.clone(),
+        master_sae_id: master_sae_id.to_string(),
+        slave_sae_ids: slave_sae_ids.to_vec(),
+        size: key_container.keys.first().map_or(0, |key| key.size),
+        keys,
+        key_container_extension: key_container.key_container_extension.clone(),
+        hop_count,
+        expires_at,
+    }
+}
+
+fn xor(left: &[u8], right: &[u8]) -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(left.iter().zip(right).map(|(l, r)| l ^ r).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_unmask_recovers_key() {
+        let key = vec![0x12, 0x34, 0x56];
+        let pad = vec![0xff, 0x00, 0xa5];
+        let masked_key = converter::to_base64(&xor(&key, &pad));
+
+        assert_eq!(*unmask(&masked_key, &pad).unwrap(), key);
+    }
+
+    #[test]
+    fn test_unmask_rejects_size_mismatch() {
+        let masked_key = converter::to_base64(&[0x12, 0x34]);
+
+        assert!(unmask(&masked_key, &[0xff]).is_err());
+    }
+
+    #[test]
+    fn test_unmask_rejects_invalid_encoding() {
+        assert_eq!(
+            unmask("not base64!", &[0xff]).unwrap_err().to_string(),
+            Error::bad_request("Invalid relayed key encoding").to_string()
+        );
+    }
+}
@@ -1,65 +1,301 @@
 // SPDX-FileCopyrightText: © 2023 Merqury Cybersecurity Ltd <info@merqury.eu>
 // SPDX-License-Identifier: AGPL-3.0-only
+use crate::common;
 use crate::config::CONFIG;
 use crate::models::connection_info::ConnectionInfo;
+use crate::ops;
 use actix_tls::accept::openssl::TlsStream;
 use actix_web::dev::Extensions;
-use actix_web::rt::net::TcpStream;
-use log::debug;
+use actix_web::rt::{
+    self,
+    net::TcpStream,
+    signal::unix::{signal, SignalKind},
+};
+use log::{debug, error, info};
 use openssl::error::ErrorStack;
 use openssl::nid::Nid;
 use openssl::ssl::{
-    SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode,
+    ClientHelloResponse, SslAcceptor, SslAcceptorBuilder, SslContext,
+    SslContextRef, SslFiletype, SslMethod, SslVerifyMode,
 };
+use openssl::x509::{X509NameRef, X509Ref, X509StoreContextRef};
 use std::any::Any;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+lazy_static! {
+    /// Context new TLS connections are accepted with, replaced whenever the
+    /// certificates are reloaded.
+    static ref TLS_CONTEXT: RwLock<Option<SslContext>> = RwLock::new(None);
+}
 
 pub fn add_cert_info_to_request_body(
     connection: &dyn Any,
     data: &mut Extensions,
 ) {
-    let tls_socket = connection
-        .downcast_ref::<TlsStream<TcpStream>>()
-        .expect("Socket should be of type TLSStream.");
-
-    let conn_info = extract_conn_info_from_socket(tls_socket);
-    debug!("Extracted connection information: {:?}", &conn_info);
+    let tls_socket = match connection.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(tls_socket) => tls_socket,
+        None => {
+            error!("Connection is not a TLS stream");
+            return;
+        }
+    };
 
-    data.insert(conn_info);
+    // NOTE: The connection cannot be refused from here, hence requests over
+    // a connection without an SAE ID are rejected with a '401' error instead.
+    if let Some(conn_info) = extract_conn_info_from_socket(tls_socket) {
+        debug!("Extracted connection information: {:?}", &conn_info);
+        data.insert(conn_info);
+    }
 }
 
 pub fn build_tls_configuration() -> Result<SslAcceptorBuilder, ErrorStack> {
+    set_tls_context(build_tls_context()?);
+
+    // NOTE: The acceptor the server is bound with cannot be replaced, hence
+    // every new connection is switched over to the current context instead.
+    // Established connections keep the context they were accepted with.
+    let mut builder = build_acceptor()?;
+    builder.set_client_hello_callback(|ssl, _| {
+        if let Some(context) = tls_context() {
+            ssl.set_ssl_context(&context)?;
+        }
+        Ok(ClientHelloResponse::SUCCESS)
+    });
+
+    Ok(builder)
+}
+
+/// Reloads the root CA, private key and certificate, for both the connections
+/// accepted from now on and those made to peer KMEs. The current ones are kept
+/// if the new ones are invalid.
+pub fn reload_tls_configuration() -> Result<(), ErrorStack> {
+    let context = build_tls_context()?;
+    ops::peer::reload()?;
+    set_tls_context(context);
+
+    Ok(())
+}
+
+/// Reloads the TLS configuration whenever SIGHUP is received.
+pub async fn run_tls_reload_on_hangup() {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP. Error: {:?}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading the TLS configuration");
+        reload_tls_configuration_or_log();
+    }
+}
+
+/// Reloads the TLS configuration whenever one of its files is modified, e.g.
+/// when the certificates are renewed.
+pub async fn run_tls_file_watcher() {
+    let mut interval = rt::time::interval(Duration::from_secs(
+        CONFIG.tls_reload_interval_secs,
+    ));
+    let mut modified = tls_files_modified();
+
+    info!("TLS file watcher started");
+
+    loop {
+        interval.tick().await;
+
+        let current = tls_files_modified();
+        if current == modified {
+            continue;
+        }
+
+        // NOTE: A failed reload is retried on the next modification, such
+        // that files being replaced one at a time are picked up once all of
+        // them match.
+        modified = current;
+        info!("TLS files modified, reloading the TLS configuration");
+        reload_tls_configuration_or_log();
+    }
+}
+
+fn reload_tls_configuration_or_log() {
+    if let Err(e) = reload_tls_configuration() {
+        error!(
+            "Failed to reload the tls configuration, keeping the current one. \
+             Error: {:?}",
+            e
+        );
+    }
+}
+
+/// Returns the TLS files, including the revocation lists, along with the time
+/// each was last modified.
+fn tls_files_modified() -> Vec<(PathBuf, Option<SystemTime>)> {
+    [&CONFIG.root_crt, &CONFIG.private_key, &CONFIG.public_crt]
+        .iter()
+        .map(PathBuf::from)
+        .chain(ops::crl::files().unwrap_or_default())
+        .map(|file| {
+            let modified =
+                fs::metadata(&file).and_then(|meta| meta.modified()).ok();
+            (file, modified)
+        })
+        .collect()
+}
+
+fn build_acceptor() -> Result<SslAcceptorBuilder, ErrorStack> {
     let mut builder = SslAcceptor::mozilla_modern_v5(SslMethod::tls())?;
 
     builder.set_ca_file(&CONFIG.root_crt)?;
+    ops::crl::load(&mut builder)?;
     builder.set_private_key_file(&CONFIG.private_key, SslFiletype::PEM)?;
     builder.set_certificate_chain_file(&CONFIG.public_crt)?;
+    builder.check_private_key()?;
 
-    builder
-        .set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    builder.set_verify_callback(
+        SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        log_rejected_certificate,
+    );
 
     Ok(builder)
 }
 
+/// Logs why a client certificate fails verification, e.g. because it was
+/// revoked, as the handshake is aborted before any request is received.
+fn log_rejected_certificate(
+    preverify_ok: bool,
+    context: &mut X509StoreContextRef,
+) -> bool {
+    if !preverify_ok {
+        let (subject, serial) = match context.current_cert() {
+            Some(cert) => {
+                (distinguished_name(cert.subject_name()), serial_number(cert))
+            }
+            None => (String::new(), String::new()),
+        };
+
+        error!(
+            "Rejected certificate '{}' with serial number {}: {}",
+            subject,
+            serial,
+            context.error()
+        );
+    }
+
+    preverify_ok
+}
+
+fn serial_number(cert: &X509Ref) -> String {
+    match cert.serial_number().to_bn().and_then(|serial| serial.to_hex_str()) {
+        Ok(serial) => serial.to_string(),
+        Err(_) => "?".to_string(),
+    }
+}
+
+fn build_tls_context() -> Result<SslContext, ErrorStack> {
+    let acceptor = build_acceptor()?.build();
+    log_certificate(acceptor.context());
+
+    Ok(acceptor.context().to_owned())
+}
+
+fn log_certificate(context: &SslContextRef) {
+    if let Some(cert) = context.certificate() {
+        info!(
+            "Serving certificate '{}', expiring {}",
+            distinguished_name(cert.subject_name()),
+            cert.not_after()
+        );
+    }
+}
+
+fn tls_context() -> Option<SslContext> {
+    common::load(&TLS_CONTEXT)
+}
+
+fn set_tls_context(context: SslContext) {
+    common::store(&TLS_CONTEXT, Some(context));
+}
+
 fn extract_conn_info_from_socket(
     tls_socket: &TlsStream<TcpStream>,
-) -> ConnectionInfo {
-    let cert = tls_socket
-        .ssl()
-        .peer_certificate()
-        .expect("Peer certificate should always be provided");
-
-    let mut common_name_entries =
-        cert.subject_name().entries_by_nid(Nid::COMMONNAME);
-
-    let common_name_entry = common_name_entries
-        .next()
-        .expect("Failed to retrieve first common name entry");
-
-    ConnectionInfo {
-        sae_id: common_name_entry
-            .data()
-            .as_utf8()
-            .expect("Could not convert common name entry to string")
-            .to_string(),
+) -> Option<ConnectionInfo> {
+    let cert = match tls_socket.ssl().peer_certificate() {
+        Some(cert) => cert,
+        None => {
+            error!("No peer certificate provided");
+            return None;
+        }
+    };
+
+    match ops::identity::sae_id(&cert) {
+        Some(sae_id) => Some(ConnectionInfo { sae_id }),
+        None => {
+            error!(
+                "Failed to extract the SAE ID from certificate '{}' with \
+                 serial number {}",
+                distinguished_name(cert.subject_name()),
+                serial_number(&cert)
+            );
+            None
+        }
+    }
+}
+
+/// Returns the first common name entry of the certificate's subject.
+pub fn common_name(cert: &X509Ref) -> Option<String> {
+    let common_name_entry =
+        cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+
+    match common_name_entry.data().as_utf8() {
+        Ok(common_name) => Some(common_name.to_string()),
+        Err(e) => {
+            error!("Could not convert common name entry to string: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Formats the name as comma separated 'field=value' entries, in the order
+/// they appear in the certificate, e.g. 'C=GB, O=Merqury, CN=kme_001'. Fields
+/// unknown to OpenSSL are named by their OID, e.g. '1.3.6.1.4.1.99999.1'.
+pub fn distinguished_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let field = match entry.object().nid() {
+                Nid::UNDEF => entry.object().to_string(),
+                nid => nid.short_name().unwrap_or("?").to_string(),
+            };
+            match entry.data().as_utf8() {
+                Ok(value) => format!("{}={}", field, value),
+                Err(_) => format!("{}=?", field),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509Name;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_distinguished_name() {
+        let mut builder = X509Name::builder().unwrap();
+        builder.append_entry_by_nid(Nid::COUNTRYNAME, "GB").unwrap();
+        builder.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Merqury").unwrap();
+        builder.append_entry_by_nid(Nid::COMMONNAME, "kme_001").unwrap();
+        builder.append_entry_by_text("1.3.6.1.4.1.99999.1", "sae_001").unwrap();
+
+        assert_eq!(
+            distinguished_name(&builder.build()),
+            "C=GB, O=Merqury, CN=kme_001, 1.3.6.1.4.1.99999.1=sae_001"
+        );
     }
 }